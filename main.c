@@ -1,222 +1,311 @@
-
-#include <limits.h>
+#include <errno.h>
 #include <stdio.h>
 #include <stdlib.h>
-#include <unistd.h>
+#include <string.h>
 #include "fixes.h"
 
-#define __USE_ISOC99 1
-#include <math.h>
-#undef __USE_ISOC99
-
-/* Call table/trapping table lookups/execution*/
-#include <signal.h>
-#include <unistd.h>
-
 #include "platform.h"
 #include "thunk.h"
 #include "util.h"
 #include "wa.h"
 
-/* WASM test files */
-
-unsigned char arith_wasm[] = {
-    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01,
-    0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x03, 0x05, 0x04, 0x00, 0x00,
-    0x00, 0x00, 0x07, 0x19, 0x04, 0x03, 0x61, 0x64, 0x64, 0x00, 0x00,
-    0x03, 0x73, 0x75, 0x62, 0x00, 0x01, 0x03, 0x6d, 0x75, 0x6c, 0x00,
-    0x02, 0x03, 0x64, 0x69, 0x76, 0x00, 0x03, 0x0a, 0x21, 0x04, 0x07,
-    0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b, 0x07, 0x00, 0x20, 0x00,
-    0x20, 0x01, 0x6b, 0x0b, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6c,
-    0x0b, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6e, 0x0b};
-unsigned int arith_wasm_len = 86;
-
-const char* arith_wasm_funcs[] = {"add",
-                                  "sub"
-                                  "mul",
-                                  "div"};
-
-unsigned char wasi_test_wasm[] = {
-    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60,
-    0x00, 0x00, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x03, 0x04, 0x03, 0x00, 0x01,
-    0x01, 0x04, 0x05, 0x01, 0x70, 0x01, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00,
-    0x02, 0x06, 0x15, 0x03, 0x7f, 0x01, 0x41, 0x80, 0x88, 0x04, 0x0b, 0x7f,
-    0x00, 0x41, 0x80, 0x88, 0x04, 0x0b, 0x7f, 0x00, 0x41, 0x80, 0x08, 0x0b,
-    0x07, 0x34, 0x05, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
-    0x0b, 0x5f, 0x5f, 0x68, 0x65, 0x61, 0x70, 0x5f, 0x62, 0x61, 0x73, 0x65,
-    0x03, 0x01, 0x0a, 0x5f, 0x5f, 0x64, 0x61, 0x74, 0x61, 0x5f, 0x65, 0x6e,
-    0x64, 0x03, 0x02, 0x03, 0x66, 0x69, 0x62, 0x00, 0x01, 0x06, 0x66, 0x69,
-    0x62, 0x52, 0x65, 0x63, 0x00, 0x02, 0x0a, 0x5c, 0x03, 0x02, 0x00, 0x0b,
-    0x2e, 0x01, 0x03, 0x7f, 0x41, 0x01, 0x21, 0x01, 0x20, 0x00, 0x41, 0x01,
-    0x6a, 0x21, 0x00, 0x41, 0x00, 0x21, 0x02, 0x03, 0x40, 0x20, 0x01, 0x22,
-    0x03, 0x20, 0x02, 0x6a, 0x21, 0x01, 0x20, 0x03, 0x21, 0x02, 0x20, 0x00,
-    0x41, 0x7f, 0x6a, 0x22, 0x00, 0x0d, 0x00, 0x0b, 0x20, 0x01, 0x0b, 0x28,
-    0x00, 0x02, 0x40, 0x02, 0x40, 0x20, 0x00, 0x45, 0x0d, 0x00, 0x20, 0x00,
-    0x41, 0x01, 0x47, 0x0d, 0x01, 0x41, 0x01, 0x0f, 0x0b, 0x41, 0x00, 0x0f,
-    0x0b, 0x20, 0x00, 0x41, 0x7f, 0x6a, 0x10, 0x82, 0x80, 0x80, 0x80, 0x00,
-    0x20, 0x00, 0x6a, 0x0b, 0x00, 0x28, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01,
-    0x21, 0x03, 0x00, 0x11, 0x5f, 0x5f, 0x77, 0x61, 0x73, 0x6d, 0x5f, 0x63,
-    0x61, 0x6c, 0x6c, 0x5f, 0x63, 0x74, 0x6f, 0x72, 0x73, 0x01, 0x03, 0x66,
-    0x69, 0x62, 0x02, 0x06, 0x66, 0x69, 0x62, 0x52, 0x65, 0x63};
-unsigned int wasi_test_wasm_len = 250;
-
-const char* wasi_test_wasm_funcs[] = {"fib", "fibRec"};
-
-/* //////////////////////////////////////////////////////// */
-/* memory layout */
-
-#ifdef LOW_MEMORY_CONFIG
-#define PAGE_COUNT 1  // 64K each
-#define TOTAL_PAGES 1
-#define TABLE_COUNT 2
-#else
-#define PAGE_COUNT 1                      // 64K each
-#define TOTAL_PAGES 0x100000 / PAGE_SIZE  // use 1MByte of memory
-#define TABLE_COUNT 20
-#endif
-
-Memory _env__memory_ = {PAGE_COUNT,   // initial size (64K pages)
-                        TOTAL_PAGES,  // max size (64K pages)
-                        PAGE_COUNT,   // current size (64K pages)
-                        NULL};        // memory base
-uint8_t* _env__memoryBase_;
-
-Table _env__table_ = {ANYFUNC,      // on;y allowed value in WASM MVP
-                      TABLE_COUNT,  // initial
-                      TABLE_COUNT,  // max
-                      TABLE_COUNT,  // current
-                      0};
-// uint32_t *_env__table_ = 0;
-uint32_t* _env__tableBase_;
-
-/*double    _global__NaN_         = NAN;*/
-/*double    _global__Infinity_    = INFINITY;*/
-
-uint32_t** _env__DYNAMICTOP_PTR_;
-uint32_t* _env__tempDoublePtr_;
-
-// Initialize memory globals
-void init_wac_eps(void) {
-    _env__memoryBase_ = calloc(PAGE_COUNT, PAGE_SIZE);
-
-    _env__tempDoublePtr_ = (uint32_t*)_env__memoryBase_;
-    _env__DYNAMICTOP_PTR_ = (uint32_t**)(_env__memoryBase_ + 16);
-
-    *_env__DYNAMICTOP_PTR_ =
-        (uint32_t*)(_env__memoryBase_ + PAGE_COUNT * PAGE_SIZE);
-
-    // This arrangement correlates to the module mangle_table_offset option
-    //    if (posix_memalign((void **)&_env__table_.entries,
-    //    sysconf(_SC_PAGESIZE),
-    //                       TABLE_COUNT*sizeof(uint32_t))) {
-    //            perror("posix_memalign");
-    _env__table_.entries = malloc(TABLE_COUNT * sizeof(uint32_t));
-    if (_env__table_.entries == NULL) {
-        //
-        perror("init_wac_eps: malloc(tablecount....)");
-        exit(1);
-    }
-    _env__tableBase_ = _env__table_.entries;
-
-    wa_info("init_mem results:\n");
-    wa_info("  _env__memory_.bytes: %p\n", _env__memory_.bytes);
-    wa_info("  _env__memoryBase_: %p\n", _env__memoryBase_);
-    wa_info("  _env__DYNAMIC_TOP_PTR_: %p\n", _env__DYNAMICTOP_PTR_);
-    wa_info("  *_env__DYNAMIC_TOP_PTR_: %p\n", *_env__DYNAMICTOP_PTR_);
-    wa_info("  _env__table_.entries: %p\n", _env__table_.entries);
-    wa_info("  _env__tableBase_: %p\n", _env__tableBase_);
+/* Exit codes */
+#define EXIT_OK 0
+#define EXIT_TRAP 1
+#define EXIT_LOAD 2
+#define EXIT_USAGE 3
+
+static void usage(char* prog) {
+    fprintf(stderr,
+            "usage: %s [--log-level N] <file.wasm> [--invoke name] [args...]\n"
+            "\n"
+            "Loads the module, which runs its start function, then calls the\n"
+            "exported function 'name', or '_start' if it is exported. The\n"
+            "arguments are typed as value:type, e.g. 1:i32 or 2.5:f64, and the\n"
+            "results are printed the same way, one per line.\n"
+            "\n"
+            "exit codes: %d success, %d trap, %d load error, %d usage error\n",
+            prog, EXIT_OK, EXIT_TRAP, EXIT_LOAD, EXIT_USAGE);
 }
 
-/////////////////////////////////////////////////////////
-// Command line
-
-int main(int argc, char** argv) {
-    char* mod_path = NULL;
-    int fidx = 0;
-    result_t res;
-    uint8_t* bytes = NULL;
-    int byte_count;
-    Options opts;
-    Module* m;
+/* Reads the whole file at path, NULL on failure */
+static uint8_t* read_file(char* path, uint32_t* byte_count) {
     FILE* f;
+    long size;
+    uint8_t* bytes;
+
+    f = fopen(path, "rb");
+    if (f == NULL) {
+        return NULL;
+    }
+    if (fseek(f, 0, SEEK_END) != 0 || (size = ftell(f)) < 0 ||
+        fseek(f, 0, SEEK_SET) != 0) {
+        fclose(f);
+        return NULL;
+    }
+
+    /* One more byte so that an empty file isn't a failed malloc */
+    bytes = malloc(size + 1);
+    if (bytes != NULL && fread(bytes, 1, size, f) != (size_t)size) {
+        free(bytes);
+        bytes = NULL;
+    }
+    fclose(f);
+
+    *byte_count = size;
+    return bytes;
+}
+
+/* Parses a decimal or 0x prefixed integer that fits in bits, negative
+ * values wrap like they do in the text format */
+static bool parse_int(char* s, uint32_t bits, uint64_t* out) {
+    uint64_t value = 0, max;
+    uint32_t base = 10, digit;
+    bool negative = false;
 
-    opts.disable_memory_bounds = false;
-    opts.dlsym_trim_underscore = false;
-    opts.mangle_table_index = false;
+    max = bits == 64 ? (uint64_t)-1 : ((uint64_t)1 << bits) - 1;
 
-    // init_wac_eps();
+    if (*s == '-' || *s == '+') {
+        negative = *s == '-';
+        s++;
+    }
+    if (s[0] == '0' && (s[1] == 'x' || s[1] == 'X')) {
+        base = 16;
+        s += 2;
+    }
+    if (*s == '\0') {
+        return false;
+    }
 
-    // Load the module
-    // bytes = arith_wasm;
-    // byte_count = arith_wasm_len;
-    // bytes =  wasi_test_wasm;
-    // byte_count =  wasi_test_wasm_len;
+    for (; *s != '\0'; s++) {
+        if (*s >= '0' && *s <= '9') {
+            digit = *s - '0';
+        } else if (base == 16 && *s >= 'a' && *s <= 'f') {
+            digit = *s - 'a' + 10;
+        } else if (base == 16 && *s >= 'A' && *s <= 'F') {
+            digit = *s - 'A' + 10;
+        } else {
+            return false;
+        }
+        if (digit >= base || value > (max - digit) / base) {
+            return false;
+        }
+        value = value * base + digit;
+    }
+
+    /* The magnitude of a negative value is at most 2^(bits-1) */
+    if (negative && value > (max >> 1) + 1) {
+        return false;
+    }
+    *out = negative ? (~value + 1) & max : value;
+    return true;
+}
 
-    f = fopen("/home/cub3d/wabt/nop.0.wasm", "rb");
+/* Parses an argument like 1:i32 or 2.5:f64 */
+static bool parse_arg(char* arg, StackValue* sv) {
+    char *sep, *end;
+    uint64_t i;
+    double d;
+    bool ok = true;
 
-    if (f) {
-        fseek(f, 0, SEEK_END);
-        byte_count = ftell(f);
-        fseek(f, 0, SEEK_SET);
-        bytes = malloc(byte_count);
-        if (bytes) {
-            (void)fread(bytes, 1, byte_count, f);
+    sep = strrchr(arg, ':');
+    if (sep == NULL || sep == arg) {
+        return false;
+    }
+    *sep = '\0';
+
+    if (strcmp(sep + 1, "i32") == 0) {
+        sv->value_type = I32;
+        ok = parse_int(arg, 32, &i);
+        sv->value.uint32 = (uint32_t)i;
+    } else if (strcmp(sep + 1, "i64") == 0) {
+        sv->value_type = I64;
+        ok = parse_int(arg, 64, &i);
+        sv->value.uint64 = i;
+    } else if (strcmp(sep + 1, "f32") == 0 || strcmp(sep + 1, "f64") == 0) {
+        errno = 0;
+        d = strtod(arg, &end);
+        ok = *end == '\0' && errno != ERANGE;
+        if (sep[2] == '3') {
+            sv->value_type = F32;
+            sv->value.f32 = (float)d;
+        } else {
+            sv->value_type = F64;
+            sv->value.f64 = d;
         }
-        fclose(f);
+    } else {
+        ok = false;
     }
 
-    if (bytes == NULL) {
-        fprintf(stderr, "Could not load %s", mod_path);
-        return 2;
+    *sep = ':';
+    return ok;
+}
+
+static void print_value(StackValue* sv) {
+    switch (sv->value_type) {
+        case I32:
+            printf("%d:i32\n", sv->value.int32);
+            break;
+        case I64:
+            printf("%lld:i64\n", (long long)sv->value.int64);
+            break;
+        case F32:
+            printf("%.9g:f32\n", sv->value.f32);
+            break;
+        case F64:
+            printf("%.17g:f64\n", sv->value.f64);
+            break;
+        default:
+            printf("<invalid 0x%x>\n", sv->value_type);
     }
+}
 
-    m = load_module(bytes, byte_count, opts);
-    m->path = "arith,wasm";
+/* Calls the export name, or _start if name is NULL and it is exported, with
+ * the arguments args. Returns the exit code */
+static int run(Instance* m, char* name, char** args, int arg_count) {
+    int i;
+    uint32_t fidx, r;
+    Type* type;
+    result_t res;
 
-    init_thunk_in(m);
+    if (name == NULL) {
+        /* The start function already ran, _start is the WASI entry point */
+        fidx = get_export_fidx(m->module, "_start", strlen("_start") + 1);
+        if (fidx == (uint32_t)-1) {
+            if (arg_count > 0) {
+                fprintf(stderr, "arguments given without --invoke\n");
+                return EXIT_USAGE;
+            }
+            return EXIT_OK;
+        }
+        name = "_start";
+    } else {
+        fidx = get_export_fidx(m->module, name, strlen(name) + 1);
+        if (fidx == (uint32_t)-1) {
+            fprintf(stderr, "no exported function named '%s'\n", name);
+            return EXIT_USAGE;
+        }
+    }
 
-    // setup argc/argv
-    m->stack[++m->sp].value_type = I32;
-    m->stack[m->sp].value.uint32 = 3;
-    //   m->stack[m->sp].value.uint32 = 361;  // mac result that fits to two
-    //   bytes
+    type = m->module->functions[fidx].type;
+    if ((uint32_t)arg_count != type->param_count) {
+        fprintf(stderr, "'%s' takes %u arguments, got %d\n", name,
+                type->param_count, arg_count);
+        return EXIT_USAGE;
+    }
+    if (arg_count > STACK_SIZE || type->result_count > STACK_SIZE) {
+        fprintf(stderr, "too many arguments\n");
+        return EXIT_USAGE;
+    }
 
-    // Invoke main/_main function and exit
-    fidx = get_export_fidx(m, "fibRec", strlen("fibRec") + 1);
-    if (fidx == -1) {
-        fidx = get_export_fidx(m, "fib", strlen("fib") + 1);
-        if (fidx == -1) {
-            FATAL("no exported function named 'fib' or 'finbRec'\n");
+    for (i = 0; i < arg_count; i++) {
+        StackValue* sv = &m->stack[++m->sp];
+        if (!parse_arg(args[i], sv)) {
+            fprintf(stderr, "invalid argument '%s'\n", args[i]);
+            return EXIT_USAGE;
+        }
+        if (sv->value_type != type->params[i]) {
+            fprintf(stderr, "argument %d of '%s' has the wrong type\n", i,
+                    name);
+            return EXIT_USAGE;
         }
     }
-    res = invoke(m, fidx);
 
+    res = invoke(m, fidx);
     if (res_err(res)) {
-        wa_error("Exception: %s\n", res_err_msg(res));
-        return 1;
-    }
-
-    if (m->sp >= 0) {
-        StackValue* result = &m->stack[m->sp--];
-        switch (result->value_type) {
-            case I32:
-                printf("I32 return value: 0x%x:i32", result->value.uint32);
-                break;
-            case I64:
-                printf("I64 return value: 0x%llx:i64", result->value.uint64);
-                break;
-            case F32:
-                printf("F32 return value: %.7g:f32", result->value.f32);
-                break;
-            case F64:
-                printf("F64 return value: %.7g:f64", result->value.f64);
-                break;
+        fprintf(stderr, "trap: %s\n", res_err_msg(res));
+        res_free(res);
+        return EXIT_TRAP;
+    }
+
+    for (r = 0; r < type->result_count; r++) {
+        print_value(&m->stack[m->sp - type->result_count + 1 + r]);
+    }
+    m->sp -= type->result_count;
+
+    return EXIT_OK;
+}
+
+int main(int argc, char** argv) {
+    char *path = NULL, *name = NULL;
+    int i, argi = argc, arg_count, log_level = 1, status;
+    uint32_t byte_count, error_pos;
+    uint8_t* bytes;
+    Options opts;
+    Module* m;
+    Instance* instance;
+    result_t res;
+
+    for (i = 1; i < argc && path == NULL; i++) {
+        if (strcmp(argv[i], "--log-level") == 0 && i + 1 < argc) {
+            log_level = atoi(argv[++i]);
+        } else if (argv[i][0] == '-' && argv[i][1] == '-') {
+            usage(argv[0]);
+            return EXIT_USAGE;
+        } else {
+            path = argv[i];
         }
-        // value_repr(&m->stack[m->sp--]);
+    }
+    if (path == NULL) {
+        usage(argv[0]);
+        return EXIT_USAGE;
+    }
+    if (i + 1 < argc && strcmp(argv[i], "--invoke") == 0) {
+        name = argv[i + 1];
+        i += 2;
+    } else if (i < argc && strcmp(argv[i], "--invoke") == 0) {
+        usage(argv[0]);
+        return EXIT_USAGE;
+    }
+    argi = i;
+    arg_count = argc - argi;
 
-    } else {
-        printf("No result.\n");
+    wa_set_log_level(log_level);
+
+    bytes = read_file(path, &byte_count);
+    if (bytes == NULL) {
+        fprintf(stderr, "could not read %s\n", path);
+        return EXIT_LOAD;
     }
-    printf("\n\n ------ :-) DONE ------\n\n");
-    return 0;
+
+    /* The file is read into memory already, the module uses it as it is */
+    memset(&opts, 0, sizeof(opts));
+    opts.execute_in_place = true;
+    res = load_module_checked(bytes, byte_count, opts, &m, &error_pos);
+    if (res_err(res)) {
+        fprintf(stderr, "could not load %s: %s at byte 0x%x\n", path,
+                res_err_msg(res), error_pos);
+        res_free(res);
+        free(bytes);
+        return EXIT_LOAD;
+    }
+    m->path = path;
+
+    res = instantiate(m, &instance);
+    if (res.status == S_ERR_NEST) {
+        /* The start function trapped */
+        fprintf(stderr, "trap: %s\n",
+                res_err_msg(*(result_t*)res.parent));
+        res_free(res);
+        instance_destroy(instance);
+        module_destroy(m);
+        free(bytes);
+        return EXIT_TRAP;
+    }
+    if (res_err(res)) {
+        fprintf(stderr, "could not instantiate %s: %s\n", path,
+                res_err_msg(res));
+        res_free(res);
+        module_destroy(m);
+        free(bytes);
+        return EXIT_LOAD;
+    }
+
+    init_thunk_in(instance);
+
+    status = run(instance, name, argv + argi, arg_count);
+    instance_destroy(instance);
+    module_destroy(m);
+    free(bytes);
+    return status;
 }
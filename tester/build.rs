@@ -0,0 +1,87 @@
+//! Generates one test per spec script found under res/, see the `test!` macro in main.rs
+
+use std::fmt::Write;
+use std::path::Path;
+
+/// Suite names that aren't valid identifiers as they are
+const RESERVED: &[&str] = &["const", "if", "loop", "return", "type", "i32", "i64"];
+
+/// The `.wast_` suites in a directory, sorted by name
+fn suites(dir: &Path) -> Vec<String> {
+    let mut suites = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {e}", dir.display()))
+        .filter_map(|e| {
+            let name = e.ok()?.file_name().into_string().ok()?;
+            let suite = name.strip_suffix(".wast_")?;
+            dir.join(&name).join(format!("{suite}.wast.json")).exists().then(|| suite.to_string())
+        })
+        .collect::<Vec<_>>();
+    suites.sort();
+    suites
+}
+
+fn ident(suite: &str) -> String {
+    let ident = suite.replace(['-', '.'], "_");
+    if RESERVED.contains(&ident.as_str()) {
+        format!("{ident}_")
+    } else {
+        ident
+    }
+}
+
+/// Whether a script has binary modules that should fail to load
+fn has_rejections(script: &Path) -> bool {
+    std::fs::read_to_string(script)
+        .unwrap()
+        .lines()
+        .any(|l| (l.contains("\"assert_invalid\"") || l.contains("\"assert_malformed\"")) && l.contains("\"module_type\": \"binary\""))
+}
+
+fn write_tests(out: &Path, file: &str, run: &str, tests: impl Iterator<Item = (String, Vec<String>)>) {
+    let mut s = format!("test! {{\n    {run};\n");
+    for (suite, proposals) in tests {
+        write!(s, "    [{}, {suite:?}", ident(&suite)).unwrap();
+        for p in proposals {
+            write!(s, ", {p:?}").unwrap();
+        }
+        s.push_str("],\n");
+    }
+    s.push_str("}\n");
+    std::fs::write(out.join(file), s).unwrap();
+}
+
+fn main() {
+    let out = std::env::var("OUT_DIR").unwrap();
+    let out = Path::new(&out);
+    let res = Path::new("res");
+    println!("cargo:rerun-if-changed=res");
+    println!("cargo:rerun-if-changed=proposals.txt");
+
+    let proposals = std::fs::read_to_string("proposals.txt").unwrap();
+    let proposals = proposals
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut words = l.split_whitespace();
+            (words.next().unwrap(), words.map(String::from).collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>();
+
+    let core = suites(&res.join("wg-1.0"));
+    write_tests(out, "core_test.rs", "crate::run_test", core.iter().map(|s| (s.clone(), Vec::new())));
+
+    let rejects = core
+        .iter()
+        .filter(|s| has_rejections(&res.join("wg-1.0").join(format!("{s}.wast_")).join(format!("{s}.wast.json"))));
+    write_tests(out, "reject_test.rs", "crate::run_reject_test", rejects.map(|s| (s.clone(), Vec::new())));
+
+    let post_mvp = suites(res).into_iter().map(|s| {
+        let p = proposals.iter().find(|(n, _)| *n == s).map(|(_, p)| p.clone()).unwrap_or_default();
+        (s, p)
+    });
+    write_tests(out, "post_mvp_test.rs", "crate::run_post_mvp_test", post_mvp);
+
+    let regression = suites(&res.join("regression"));
+    write_tests(out, "regression_test.rs", "crate::run_regression_test", regression.into_iter().map(|s| (s, Vec::new())));
+}
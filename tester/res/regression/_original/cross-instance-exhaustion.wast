@@ -0,0 +1,23 @@
+;; Two instances calling each other through a shared table, each call into
+;; the other instance recurses in the interpreter until the nesting limit
+
+(module $A
+  (type $t (func (param i32) (result i32)))
+  (table (export "table") 2 funcref)
+  (elem (i32.const 0) $ping)
+  (func $ping (export "ping") (type $t)
+    (call_indirect (type $t) (local.get 0) (i32.const 1))
+  )
+)
+(register "A" $A)
+
+(module $B
+  (type $t (func (param i32) (result i32)))
+  (import "A" "table" (table 2 funcref))
+  (elem (i32.const 1) $pong)
+  (func $pong (type $t)
+    (call_indirect (type $t) (local.get 0) (i32.const 0))
+  )
+)
+
+(assert_exhaustion (invoke $A "ping" (i32.const 0)) "call stack exhausted")
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::ffi::CStr;
-use std::path::PathBuf;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use serde::Deserialize;
@@ -18,17 +19,25 @@ pub struct R {
     status: S,
     msg: *const i8,
     p: *mut R,
+    msg_owned: CBool,
 }
 
 impl R {
-    pub fn safe_r(&self) -> SafeR {
+    /// Converts the result and releases what the engine allocated for it
+    pub fn into_safe(self) -> SafeR {
+        let r = self.safe_r();
+        res_free(self);
+        r
+    }
+
+    fn safe_r(&self) -> SafeR {
         unsafe {
             match self.status {
                 S::Ok => SafeR::Ok,
-                S::Err => SafeR::Err(CStr::from_ptr(self.msg).to_str().unwrap().to_string()),
+                S::Err => SafeR::Err(CStr::from_ptr(self.msg).to_string_lossy().into_owned()),
                 S::ErrNest => SafeR::ErrNest(
-                    CStr::from_ptr(self.msg).to_str().unwrap().to_string(),
-                    Box::new(self.p.read().safe_r())
+                    CStr::from_ptr(self.msg).to_string_lossy().into_owned(),
+                    Box::new((*self.p).safe_r())
                 )
             }
         }
@@ -41,6 +50,17 @@ pub enum SafeR {
     ErrNest(String, Box<SafeR>),
 }
 
+impl SafeR {
+    /// The innermost error message, this is the trap reason set by the interpreter
+    pub fn cause(&self) -> Option<&str> {
+        match self {
+            SafeR::Ok => None,
+            SafeR::Err(s) => Some(s),
+            SafeR::ErrNest(s, p) => p.cause().or(Some(s)),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct T {
     commands: Vec<C>,
@@ -65,6 +85,10 @@ enum Arg {
     F64{
         value: String,
     },
+    #[serde(rename = "funcref")]
+    FuncRef{},
+    #[serde(rename = "externref")]
+    ExternRef{},
 }
 
 impl Arg {
@@ -119,6 +143,7 @@ impl Arg {
                     }
                 }
             }
+            Arg::FuncRef { .. } | Arg::ExternRef { .. } => panic!("reference types are not supported"),
         }
     }
 }
@@ -132,8 +157,11 @@ enum A {
         args: Vec<Arg>,
         module: Option<String>,
     },
-#[serde(rename = "get")]
-Get{},
+    #[serde(rename = "get")]
+    Get {
+        field: String,
+        module: Option<String>,
+    },
 
 }
 
@@ -144,6 +172,7 @@ enum C {
     Module{
         name: Option<String>,
         filename: String,
+        line: u64,
     },
     #[serde(rename = "action")]
     Action{
@@ -159,46 +188,410 @@ enum C {
     },
     #[serde(rename = "assert_invalid")]
     AssertInvalid {
-        // action: A,
-        // expected: Vec<Arg>,
+        filename: String,
+        text: String,
+        module_type: String,
+        line: u64,
     },
     #[serde(rename = "assert_malformed")]
     AssertMalformed {
-        // action: A,
-        // expected: Vec<Arg>,
+        filename: String,
+        text: String,
+        module_type: String,
+        line: u64,
     },
     #[serde(rename = "assert_trap")]
     AssertTrap {
-        // action: A,
-        // expected: Vec<Arg>,
+        action: A,
+        line: u64,
+        text: String,
     },
     #[serde(rename = "assert_exhaustion")]
     AssertExhaustion {
-        // action: A,
-        // expected: Vec<Arg>,
+        action: A,
+        line: u64,
+        text: String,
     },
     #[serde(rename = "assert_uninstantiable")]
     AssertUninstantiable {
-        // action: A,
-        // expected: Vec<Arg>,
+        filename: String,
+        text: String,
+        line: u64,
     },
     #[serde(rename = "assert_unlinkable")]
     AssertUnlinkable {
-        // action: A,
-        // expected: Vec<Arg>,
+        filename: String,
+        text: String,
+        line: u64,
     },
     #[serde(rename = "register")]
     Register {
-        // action: A,
-        // expected: Vec<Arg>,
+        name: Option<String>,
+        #[serde(rename = "as")]
+        as_: String,
+        line: u64,
+    }
+}
+
+impl C {
+    fn line(&self) -> u64 {
+        match self {
+            C::Module { line, .. }
+            | C::Action { line, .. }
+            | C::AssertReturn { line, .. }
+            | C::AssertInvalid { line, .. }
+            | C::AssertMalformed { line, .. }
+            | C::AssertTrap { line, .. }
+            | C::AssertExhaustion { line, .. }
+            | C::AssertUninstantiable { line, .. }
+            | C::AssertUnlinkable { line, .. }
+            | C::Register { line, .. } => *line,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            C::Module { .. } => "module",
+            C::Action { .. } => "action",
+            C::AssertReturn { .. } => "assert_return",
+            C::AssertInvalid { .. } => "assert_invalid",
+            C::AssertMalformed { .. } => "assert_malformed",
+            C::AssertTrap { .. } => "assert_trap",
+            C::AssertExhaustion { .. } => "assert_exhaustion",
+            C::AssertUninstantiable { .. } => "assert_uninstantiable",
+            C::AssertUnlinkable { .. } => "assert_unlinkable",
+            C::Register { .. } => "register",
+        }
     }
 }
 
 #[repr(C)]
 struct O {
-    disable_memory_bounds: bool,
-    mangle_table_index: bool,
-    dlsym_trim_underscore: bool,
+    disable_memory_bounds: CBool,
+    mangle_table_index: CBool,
+    dlsym_trim_underscore: CBool,
+    resolve_import: Option<ResolveFn>,
+    resolve_env: *mut libc::c_void,
+    execute_in_place: CBool,
+}
+
+impl Default for O {
+    fn default() -> Self {
+        O {
+            disable_memory_bounds: 0,
+            mangle_table_index: 0,
+            dlsym_trim_underscore: 0,
+            resolve_import: None,
+            resolve_env: core::ptr::null_mut(),
+            execute_in_place: 0,
+        }
+    }
+}
+
+/// `bool` in fixes.h
+type CBool = i32;
+
+type ResolveFn = extern "C" fn(*mut libc::c_void, *const libc::c_char, *const libc::c_char, u32, *mut Import) -> CBool;
+type HostFn = extern "C" fn(*mut Instance, *const Ty, *mut libc::c_void) -> R;
+type GetExportFn = extern "C" fn(*mut Module, *const u8, u32) -> *mut Export;
+type InvokeFn = extern "C" fn(*mut Instance, u32) -> R;
+
+// The engine, built and linked statically by the wasm89 crate
+use wasm89 as _;
+
+unsafe extern "C" {
+    fn load_module(bytes: *const u8, byte_count: u32, options: O) -> *mut Module;
+    fn load_module_checked(bytes: *const u8, byte_count: u32, options: O, module: *mut *mut Module, error_pos: *mut u32) -> R;
+    safe fn get_export_fidx(m: *mut Module, name: *const u8, name_len: u32) -> u32;
+    safe fn get_export(m: *mut Module, name: *const u8, name_len: u32) -> *mut Export;
+    safe fn get_export_value(instance: *mut Instance, exp: *mut Export) -> *mut libc::c_void;
+    safe fn instantiate(m: *mut Module, instance: *mut *mut Instance) -> R;
+    safe fn invoke(instance: *mut Instance, fidx: u32) -> R;
+    safe fn res_free(r: R);
+    safe fn wa_set_log_level(level: i32);
+}
+
+const KIND_FUNCTION: u32 = 0;
+const KIND_TABLE: u32 = 1;
+const KIND_MEMORY: u32 = 2;
+const KIND_GLOBAL: u32 = 3;
+
+use wasm89::ffi::{layout, PAGE_SIZE, STACK_SIZE};
+
+#[repr(C)]
+struct Ty {
+    form: u8,
+    param_count: u32,
+    params: *const u32,
+    result_count: u32,
+    results: *const u32,
+    mask: u64,
+}
+
+#[repr(C)]
+struct Export {
+    name: *const libc::c_char,
+    name_len: u32,
+    kind: u32,
+    index: u32,
+}
+
+#[repr(C)]
+struct Import {
+    kind: u32,
+    val: *mut libc::c_void,
+    host_func: Option<HostFn>,
+    host_env: *mut libc::c_void,
+    ty: *const Ty,
+    mutability: CBool,
+}
+
+#[repr(C)]
+#[derive(Clone)]
+struct Funcref {
+    instance: *mut Instance,
+    fidx: u32,
+}
+
+#[repr(C)]
+struct Table {
+    elem_type: u8,
+    initial: u32,
+    maximum: u32,
+    size: u32,
+    entries: *mut Funcref,
+}
+
+#[repr(C)]
+struct Memory {
+    initial: u32,
+    maximum: u32,
+    pages: u32,
+    bytes: *mut u8,
+}
+
+// The mirrors are checked against wa.h in the configuration the engine is built in
+wasm89::assert_layout!(layout::result_t, R { status, msg, p: parent, msg_owned });
+wasm89::assert_layout!(layout::Options, O {
+    disable_memory_bounds, mangle_table_index, dlsym_trim_underscore, resolve_import, resolve_env, execute_in_place,
+});
+wasm89::assert_layout!(layout::Type, Ty { form, param_count, params, result_count, results, mask });
+wasm89::assert_layout!(layout::Export, Export { name: export_name, name_len, kind: external_kind, index });
+wasm89::assert_layout!(layout::Import, Import { kind, val, host_func, host_env, ty: r#type, mutability });
+wasm89::assert_layout!(layout::Funcref, Funcref { instance, fidx });
+wasm89::assert_layout!(layout::Table, Table { elem_type, initial, maximum, size, entries });
+wasm89::assert_layout!(layout::Memory, Memory { initial, maximum, pages, bytes });
+wasm89::assert_layout!(layout::StackValue, SV { value_ty: value_type, v: value });
+wasm89::assert_layout!(layout::Instance, Instance { module, pc, sp, fp, stack, .. });
+
+/// The `spectest.print*` functions and their parameters, none of them has results
+const PRINTS: &[(&str, &[u32])] = &[
+    ("print", &[]),
+    ("print_i32", &[0x7f]),
+    ("print_i64", &[0x7e]),
+    ("print_f32", &[0x7d]),
+    ("print_f64", &[0x7c]),
+    ("print_i32_f32", &[0x7f, 0x7d]),
+    ("print_f64_f64", &[0x7c, 0x7c]),
+];
+
+/// The `spectest` module provided by the reference interpreter
+struct Spectest {
+    prints: Vec<Ty>,
+    global_i32: SV,
+    global_i64: SV,
+    global_f32: SV,
+    global_f64: SV,
+    table: Table,
+    entries: Vec<Funcref>,
+    memory: Memory,
+}
+
+impl Spectest {
+    fn new() -> Box<Self> {
+        let mut s = Box::new(Spectest {
+            prints: PRINTS
+                .iter()
+                .map(|(_, params)| Ty {
+                    form: 0x60,
+                    param_count: params.len() as u32,
+                    params: params.as_ptr(),
+                    result_count: 0,
+                    results: core::ptr::null(),
+                    mask: 0,
+                })
+                .collect(),
+            global_i32: SV { value_ty: 0x7f, v: SVV { i32: 666 } },
+            global_i64: SV { value_ty: 0x7e, v: SVV { i64: 666 } },
+            global_f32: SV { value_ty: 0x7d, v: SVV { f32: 666.6 } },
+            global_f64: SV { value_ty: 0x7c, v: SVV { f64: 666.6 } },
+            table: Table {
+                elem_type: 0x70,
+                initial: 10,
+                maximum: 20,
+                size: 10,
+                entries: core::ptr::null_mut(),
+            },
+            // Uninitialized entries, as set by instantiate
+            entries: vec![Funcref { instance: core::ptr::null_mut(), fidx: 0 }; 10],
+            // Allocated by the C library, an importer reallocates it on grow
+            memory: Memory {
+                initial: 1,
+                maximum: 2,
+                pages: 1,
+                bytes: unsafe { libc::calloc(PAGE_SIZE, 1) as *mut u8 },
+            },
+        });
+        s.table.entries = s.entries.as_mut_ptr();
+        s
+    }
+
+    fn resolve(&mut self, field: &str, kind: u32, import: &mut Import) -> bool {
+        // The loader reports a field of another kind as incompatible
+        let found = match field {
+            _ if PRINTS.iter().any(|(name, _)| *name == field) => KIND_FUNCTION,
+            "global_i32" | "global_i64" | "global_f32" | "global_f64" => KIND_GLOBAL,
+            "table" => KIND_TABLE,
+            "memory" => KIND_MEMORY,
+            _ => return false,
+        };
+        if found != kind {
+            import.kind = found;
+            return true;
+        }
+
+        match (kind, field) {
+            (KIND_FUNCTION, _) => {
+                // The loader checks the type the function is imported with
+                let p = PRINTS.iter().position(|(name, _)| *name == field).unwrap();
+                import.host_func = Some(spectest_print);
+                import.ty = &self.prints[p];
+            }
+            (KIND_GLOBAL, "global_i32") => import.val = &mut self.global_i32 as *mut SV as *mut libc::c_void,
+            (KIND_GLOBAL, "global_i64") => import.val = &mut self.global_i64 as *mut SV as *mut libc::c_void,
+            (KIND_GLOBAL, "global_f32") => import.val = &mut self.global_f32 as *mut SV as *mut libc::c_void,
+            (KIND_GLOBAL, "global_f64") => import.val = &mut self.global_f64 as *mut SV as *mut libc::c_void,
+            (KIND_TABLE, "table") => import.val = &mut self.table as *mut Table as *mut libc::c_void,
+            (KIND_MEMORY, "memory") => import.val = &mut self.memory as *mut Memory as *mut libc::c_void,
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Drop for Spectest {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.memory.bytes as *mut libc::c_void) };
+    }
+}
+
+/// Print and pop the arguments of a `spectest.print*` call
+extern "C" fn spectest_print(m: *mut Instance, ty: *const Ty, _env: *mut libc::c_void) -> R {
+    let (m, ty) = unsafe { (&mut *m, &*ty) };
+
+    let base = m.sp - ty.param_count as i32;
+    let args = (1..=ty.param_count as i32)
+        .map(|p| m.stack[(base + p) as usize].safe())
+        .collect::<Vec<_>>();
+    println!("spectest print {args:?}");
+    m.sp = base;
+
+    R {
+        status: S::Ok,
+        msg: core::ptr::null(),
+        p: core::ptr::null_mut(),
+        msg_owned: 0,
+    }
+}
+
+/// Modules registered with the `register` command, used to resolve the imports of later modules
+struct Linker {
+    registered: HashMap<String, *mut Instance>,
+    spectest: Box<Spectest>,
+    // Boxed so the host_env pointers stay valid as the Vec grows
+    #[allow(clippy::vec_box)]
+    linked: Vec<Box<Linked>>,
+    get_export: GetExportFn,
+    invoke: InvokeFn,
+}
+
+/// A function import that calls an export of another module
+struct Linked {
+    instance: *mut Instance,
+    fidx: u32,
+    invoke: InvokeFn,
+}
+
+extern "C" fn resolve_import(env: *mut libc::c_void, module: *const libc::c_char, field: *const libc::c_char, kind: u32, import: *mut Import) -> CBool {
+    let linker = unsafe { &mut *(env as *mut Linker) };
+    let (module, field) = unsafe { (CStr::from_ptr(module), CStr::from_ptr(field)) };
+
+    let module = module.to_str().unwrap_or_default();
+    if module == "spectest" && !linker.registered.contains_key(module) {
+        return linker.spectest.resolve(field.to_str().unwrap_or_default(), kind, unsafe { &mut *import }) as CBool;
+    }
+
+    let Some(&m) = linker.registered.get(module) else {
+        return 0;
+    };
+    let field = field.to_bytes_with_nul();
+    let exp = (linker.get_export)(unsafe { (*m).module }, field.as_ptr(), field.len() as u32);
+    let Some(exp) = (unsafe { exp.as_ref() }) else {
+        return 0;
+    };
+
+    // The loader checks the kind, type and mutability of what is found against the import
+    let import = unsafe { &mut *import };
+    import.kind = exp.kind;
+    match exp.kind {
+        KIND_FUNCTION => {
+            let mut linked = Box::new(Linked {
+                instance: m,
+                fidx: exp.index,
+                invoke: linker.invoke,
+            });
+            import.host_func = Some(call_linked);
+            import.host_env = linked.as_mut() as *mut Linked as *mut libc::c_void;
+            let func = get_export_value(m, exp as *const Export as *mut Export) as *const wasm89::ffi::Block;
+            import.ty = unsafe { (*func).ty as *const Ty };
+            linker.linked.push(linked);
+        }
+        _ => {
+            // Tables, memories and globals are shared with the exporting instance
+            import.val = get_export_value(m, exp as *const Export as *mut Export);
+            if exp.kind == KIND_GLOBAL {
+                let module = unsafe { &*((*m).module as *const wasm89::ffi::Module) };
+                import.mutability = unsafe { *module.global_mutable.add(exp.index as usize) };
+            }
+        }
+    }
+    1
+}
+
+/// Move the arguments onto the stack of the exporting module, call it and move the results back
+extern "C" fn call_linked(m: *mut Instance, ty: *const Ty, env: *mut libc::c_void) -> R {
+    let linked = unsafe { &*(env as *const Linked) };
+    let (m, ty, target) = unsafe { (&mut *m, &*ty, &mut *linked.instance) };
+
+    let base = m.sp - ty.param_count as i32;
+    for p in 1..=ty.param_count as i32 {
+        target.sp += 1;
+        target.stack[target.sp as usize] = m.stack[(base + p) as usize];
+    }
+    m.sp = base;
+
+    let r = (linked.invoke)(target, linked.fidx);
+    if !matches!(r.status, S::Ok) {
+        return r;
+    }
+
+    let base = target.sp - ty.result_count as i32;
+    for p in 1..=ty.result_count as i32 {
+        m.sp += 1;
+        m.stack[m.sp as usize] = target.stack[(base + p) as usize];
+    }
+    target.sp = base;
+    r
 }
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SafeSV {
@@ -247,17 +640,15 @@ impl PartialEq<Self> for SV {
             return false;
         }
 
+        // Floats are compared by their bits, so NaNs with the same payload are equal and -0 != +0
         match self.value_ty {
-            0x7f => unsafe {
-                self.v.i32 == other.v.i32
-            }
-            0x7d => unsafe {
-                self.v.f32 == other.v.f32
+            0x7f | 0x7d => unsafe {
+                self.v.u32 == other.v.u32
             }
-            0x7c => unsafe {
-                self.v.f64 == other.v.f64
+            0x7e | 0x7c => unsafe {
+                self.v.u64 == other.v.u64
             }
-            _ => {panic!("{:x}", self.value_ty)}
+            _ => false,
         }
     }
 }
@@ -266,197 +657,368 @@ impl Eq for SV {
 
 }
 
+/// Only handled through pointers
+enum Module {}
+
+/// The module and runtime state at the start of `Instance`, the rest is only touched by the engine
 #[repr(C)]
-struct Module {
+struct Instance {
+    module: *mut Module,
     pc: u32,
-    sp: u32,
-    fp: u32,
-    stack: [SV; 4*1024],
+    sp: i32,
+    fp: i32,
+    stack: [SV; STACK_SIZE],
 }
 
 
+/// `[name, "suite", "proposal"...]`, generated by build.rs for every suite in res/. Suites that need a
+/// proposal only run when its feature is enabled
 macro_rules! test {
-    ($([$name: ident, $path: expr]),*,) => {
+    ($run: path; $([$name: ident, $path: expr $(, $proposal: literal)*]),*,) => {
         $(
             #[test]
-
+            // The first missing proposal is reported, the rest are unused
+            #[allow(unused_attributes)]
+            $(#[cfg_attr(not(feature = $proposal), ignore = $proposal)])*
                 pub fn $name() {
-                    crate::run_test($path)
+                    $crate::isolated(module_path!(), stringify!($name), || $run($path))
             }
         )*
     };
 }
 
 mod core_test {
-    test! {
-        [address, "address"],
-        [align, "align"],
-        // [binary, "binary"], // t
-        [binary_leb128, "binary-leb128"],
-        [block, "block"],
-        [br, "br"],
-        [br_if, "br_if"],
-        // [br_table, "br_table"], //t
-        [break_drop, "break-drop"],
-        [call, "call"],
-        [call_indirect, "call_indirect"],
-        [comments, "comments"],
-        [const_, "const"],
-        [conversions, "conversions"],
-        // [custom, "custom"], //t
-        // [data, "data"], //import
-        // [elem, "elem"], // import
-        [endianness, "endianness"],
-        // [exports, "exports"], // bug in harness
-        [f32, "f32"],
-        [f32_bitwise, "f32_bitwise"],
-        [f32_cmp, "f32_cmp"],
-        [f64, "f64"],
-        [f64_bitwise, "f64_bitwise"],
-        [f64_cmp, "f64_cmp"],
-        [fac, "fac"],
-        [float_exprs, "float_exprs"],
-        [float_literals, "float_literals"],
-        [float_memory, "float_memory"],
-        [float_misc, "float_misc"],
-        [forward, "forward"],
-        [func, "func"],
-        [func_ptrs, "func_ptrs"],
-        // [globals, "globals"], // imports / ubsan
-        [i32_, "i32"],
-        [i64_, "i64"],
-        [if_, "if"],
-        // [imports, "imports"], // bug
-        [inline_module, "inline-module"],
-        [int_exprs, "int_exprs"],
-        [int_literals, "int_literals"],
-        [labels, "labels"],
-        [left_to_right, "left-to-right"],
-        // [linking, "linking"], // harness bug
-        [load, "load"],
-        [local_get, "local_get"],
-        [local_set, "local_set"],
-        [local_tee, "local_tee"],
-        [loop_, "loop"],
-        [memory, "memory"],
-        [memory_grow, "memory_grow"], // note: fails under ubsan when f32_cmp is enabled, but not under scudo or asan and not due to a *san error?
-        [memory_redundancy, "memory_redundancy"],
-        [memory_size, "memory_size"],
-        [memory_trap, "memory_trap"],
-        [names, "names"],
-        [nop, "nop"],
-        [return_, "return"],
-        [select, "select"],
-        [skip_stack_guard_page, "skip-stack-guard-page"],
-        [stack, "stack"],
-        [start, "start"],
-        [store, "store"],
-        [switch, "switch"],
-        [token, "token"],
-        [traps, "traps"],
-        [type_, "type"],
-        [unreachable, "unreachable"],
-        [unreached_invalid, "unreached-invalid"],
-        [unwind, "unwind"],
-        [utf8_custom_section_id, "utf8-custom-section-id"],
-        [utf8_import_field, "utf8-import-field"],
-        [utf8_import_module, "utf8-import-module"],
-        [utf8_invalid_encoding, "utf8-invalid-encoding"],
+    include!(concat!(env!("OUT_DIR"), "/core_test.rs"));
 }
+
+mod post_mvp_test {
+    include!(concat!(env!("OUT_DIR"), "/post_mvp_test.rs"));
+}
+
+mod reject_test {
+    include!(concat!(env!("OUT_DIR"), "/reject_test.rs"));
+}
+
+mod regression_test {
+    include!(concat!(env!("OUT_DIR"), "/regression_test.rs"));
 }
 
+const USAGE: &str = "usage: tester [--log-level N] [--verbose] <script.wast.json>...";
+
+/// Run wast2json scripts and report every command, exits with 1 if any of them failed
 fn main() {
+    let mut scripts = Vec::new();
+    let mut log_level = 0;
+    let mut verbose = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--log-level" => log_level = args.next().and_then(|l| l.parse().ok()).unwrap_or_else(|| usage()),
+            "--verbose" => verbose = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ => scripts.push(arg),
+        }
+    }
+    if scripts.is_empty() {
+        usage();
+    }
+
+    // The report goes to the real stdout, the progress output of the harness and library only with --verbose
+    let mut out = unsafe {
+        use std::os::fd::FromRawFd;
+        let out = libc::dup(libc::STDOUT_FILENO);
+        if !verbose {
+            let null = libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY);
+            libc::dup2(null, libc::STDOUT_FILENO);
+            libc::close(null);
+        }
+        std::fs::File::from_raw_fd(out)
+    };
+
+    wa_set_log_level(log_level);
+    std::panic::set_hook(Box::new(|_| {}));
+
+    let (mut passed, mut failed) = (0, 0);
+    for script in &scripts {
+        let name = Path::new(script).file_name().map_or(script.clone(), |n| n.to_string_lossy().into_owned());
+        run_commands(&name, script, true, |line, kind, r| {
+            use std::io::Write;
+            match r {
+                Ok(()) => {
+                    passed += 1;
+                    let _ = writeln!(out, "{script}:{line} {kind} ok");
+                }
+                Err(e) => {
+                    failed += 1;
+                    let msg = e
+                        .downcast_ref::<String>()
+                        .map(String::as_str)
+                        .or_else(|| e.downcast_ref::<&str>().copied())
+                        .unwrap_or("panicked");
+                    let _ = writeln!(out, "{script}:{line} {kind} FAILED: {msg}");
+                }
+            }
+        });
+    }
 
+    use std::io::Write;
+    let _ = writeln!(out, "{passed} passed, {failed} failed");
+    if failed > 0 {
+        std::process::exit(1);
+    }
 }
-// For asan:  ASAN_OPTIONS=detect_leaks=0 LD_PRELOAD=/usr/lib/clang/18/lib/linux/libclang_rt.asan-x86_64.so cargo test -- --test-threads=1 "core_test::" --nocapture
-// For ubsan:  ASAN_OPTIONS=detect_leaks=0 LD_PRELOAD=/usr/lib/clang/18/lib/linux/libclang_rt.ubsan_standalone-x86_64.so cargo test -- --test-threads=1 "core_test::" --nocapture
 
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
 
+/// Load a module that should be rejected, returning the reason it was rejected.
+/// The engine may still crash or exit on a bad module, so the load happens in a forked child with
+/// its stderr captured.
+fn load_rejected(bytes: &[u8]) -> Result<String, String> {
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    let mut fds = [0; 2];
+    let (pid, out) = unsafe {
+        assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+        let pid = libc::fork();
+        assert_ne!(pid, -1);
+        if pid == 0 {
+            // The child only runs the loader, it must not return into the harness
+            let null = libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY);
+            libc::dup2(null, 1);
+            libc::dup2(fds[1], 2);
+            let (mut mo, mut pos) = (std::ptr::null_mut(), 0);
+            let r = load_module_checked(bytes.as_ptr(), bytes.len() as u32, O::default(), &mut mo, &mut pos);
+            if mo.is_null() {
+                let reason = format!("{} at byte 0x{pos:x}\n", CStr::from_ptr(r.msg).to_string_lossy());
+                libc::write(2, reason.as_ptr().cast(), reason.len());
+            }
+            libc::_exit(if mo.is_null() { 2 } else { 0 });
+        }
+        libc::close(fds[1]);
+        (pid, std::fs::File::from_raw_fd(fds[0]))
+    };
 
-pub fn run_test(testset: &'static str) {
-    let mode = if std::env::vars().any(|(_, y)| y.contains("libclang_rt.asan")) {
-        println!("Using asan");
-        c"../bin/libwasm89_asan.so"
-    } else if std::env::vars().any(|(_, y)| y.contains("libclang_rt.ubsan")) {
-        println!("Using ubsan");
-        c"../bin/libwasm89_ubsan.so"
-    } else {
-        println!("Using standard build");
-        c"../bin/libwasm89.so"
+    let mut stderr = Vec::new();
+    let mut out = out;
+    out.read_to_end(&mut stderr).unwrap();
+    let mut status = 0;
+    unsafe {
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+    }
+
+    let stderr = String::from_utf8_lossy(&stderr);
+    let reason = stderr.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("").trim().to_string();
+    if libc::WIFSIGNALED(status) {
+        return Err(format!("loader crashed with signal {}", libc::WTERMSIG(status)));
+    }
+    match libc::WEXITSTATUS(status) {
+        0 => Err("module loaded".to_string()),
+        _ => Ok(reason),
+    }
+}
+
+/// Set in the child process that runs a single test
+const ISOLATED_ENV: &str = "WASM89_ISOLATED";
+
+/// Run a test in a fresh copy of the test binary, so it gets its own copy of the library and a crash
+/// or exit inside it only fails this test
+pub fn isolated(module: &str, name: &str, run: impl FnOnce()) {
+    if std::env::var_os(ISOLATED_ENV).is_some() {
+        run();
+        return;
+    }
+
+    // module_path!() includes the crate name, test names don't
+    let module = module.split_once("::").map_or("", |(_, m)| m);
+    let test = format!("{module}::{name}");
+    let xfail = known_failures().into_iter().find(|x| x.test == test && x.line.is_none());
+    let out = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([test.as_str(), "--exact", "--include-ignored", "--nocapture", "--test-threads=1"])
+        .env(ISOLATED_ENV, "1")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    print!("{stdout}");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    eprint!("{stderr}");
+
+    for l in stdout.lines().filter(|l| l.contains(" XFAIL: ")) {
+        report(l);
+    }
+
+    if out.status.success() {
+        if xfail.is_some() {
+            panic!("{test} XPASS, remove it from xfail.txt");
+        }
+        return;
+    }
+
+    if let Some(xfail) = xfail {
+        report(&format!("{test} XFAIL: {}", xfail.reason));
+        return;
+    }
+
+    let reason = match out.status.signal() {
+        Some(sig) => format!("killed by signal {sig}"),
+        None => format!("exited with {}", out.status.code().unwrap_or(-1)),
     };
-    let (load_module, get_export_fidx, invoke, _snap, _snap_dest) = unsafe {
-        let l = libc::dlopen(mode.as_ptr(), libc::RTLD_NOW);
-        assert_ne!(l, core::ptr::null_mut());
-        let load_module = libc::dlsym(l, c"load_module".as_ptr());
-        assert_ne!(load_module, core::ptr::null_mut());
-        let load_module = core::mem::transmute::<*mut libc::c_void, extern "C" fn(*const u8, usize, O)->*mut Module>(load_module);
-
-        let get_export_fidx = libc::dlsym(l, c"get_export_fidx".as_ptr());
-        assert_ne!(get_export_fidx, core::ptr::null_mut());
-        let get_export_fidx = core::mem::transmute::<*mut libc::c_void, extern "C" fn(*mut Module, *const u8, u32)->usize>(get_export_fidx);
-
-        let invoke = libc::dlsym(l, c"invoke".as_ptr());
-        assert_ne!(invoke, core::ptr::null_mut());
-        let invoke = core::mem::transmute::<*mut libc::c_void, extern "C" fn(*mut Module, usize)->R>(invoke);
-
-        let snapshot = libc::dlsym(l, c"snapshot".as_ptr());
-        assert_ne!(snapshot, core::ptr::null_mut());
-        let snapshot = core::mem::transmute::<*mut libc::c_void, extern "C" fn(*mut Module)->*mut Module>(snapshot);
-
-        let snapshot_dest = libc::dlsym(l, c"snapshot_destroy".as_ptr());
-        assert_ne!(snapshot_dest, core::ptr::null_mut());
-        let snapshot_dest = core::mem::transmute::<*mut libc::c_void, extern "C" fn(*mut Module)>(snapshot_dest);
-
-        (load_module, get_export_fidx, invoke, snapshot, snapshot_dest)
+    // The panic message if there was one, otherwise whatever the library printed last
+    let mut lines = stderr.lines();
+    let last = match lines.by_ref().position(|l| l.contains("panicked at")) {
+        Some(_) => lines.next(),
+        None => stderr.lines().rev().find(|l| !l.trim().is_empty()),
     };
+    panic!("{test} {reason}: {}", last.unwrap_or_default());
+}
 
-    // let testset = "i32";
-    // let testset = "i64";
-    // let testset = "address";
+/// Print past the test output capture, so known failures show up in a normal run
+fn report(line: &str) {
+    use std::io::Write;
+    let _ = writeln!(std::io::stderr(), "{line}");
+}
 
-    // let conf = PathBuf::from_str("res/const/const.json").unwrap();
-    let conf = PathBuf::from_str(&format!("res/wg-1.0/{testset}.wast_/{testset}.wast.json")).unwrap();
+/// An entry in xfail.txt, a whole test or a single command of a script
+struct XFail {
+    test: &'static str,
+    line: Option<u64>,
+    reason: &'static str,
+}
+
+fn known_failures() -> Vec<XFail> {
+    include_str!("../xfail.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let (key, reason) = l.split_once(' ').unwrap_or((l, ""));
+            let (test, line) = match key.rsplit_once(':').map(|(t, n)| (t, n.parse().ok())) {
+                Some((test, Some(line))) => (test, Some(line)),
+                _ => (key, None),
+            };
+            XFail { test, line, reason: reason.trim() }
+        })
+        .collect()
+}
 
-    // let t: T = serde_json::from_str(include_str!("../res/nop/nop.json")).unwrap();
-    let t: T = serde_json::from_str(&std::fs::read_to_string(&conf).expect(&format!("Failed to find {}", conf.display()))).unwrap();
+pub fn run_test(testset: &'static str) {
+    run_script(&format!("core_test::{testset}"), testset, &format!("res/wg-1.0/{testset}.wast_/{testset}.wast.json"))
+}
+
+/// Run a suite from the current spec, these need post-MVP proposals
+pub fn run_post_mvp_test(testset: &'static str) {
+    run_script(&format!("post_mvp_test::{testset}"), testset, &format!("res/{testset}.wast_/{testset}.wast.json"))
+}
+
+/// Run a hand-written script for an engine bug that the spec suites don't catch
+pub fn run_regression_test(testset: &'static str) {
+    let path = format!("res/regression/{testset}.wast_/{testset}.wast.json");
+    run_script(&format!("regression_test::{testset}"), testset, &path)
+}
+
+fn run_script(test: &str, testset: &str, path: &str) {
+    // Commands listed in xfail.txt are allowed to fail
+    let xfails = known_failures().into_iter().filter(|x| x.test == test && x.line.is_some()).collect::<Vec<_>>();
+    let mut xpass = Vec::new();
+
+    run_commands(testset, path, false, |line, _, r| check_outcome(test, &xfails, &mut xpass, line, r));
+
+    assert!(xpass.is_empty(), "{test} XPASS at lines {xpass:?}, remove them from xfail.txt");
+}
+
+/// Fail on the outcome of the command at `line` unless xfails expects it, collecting the lines that pass unexpectedly
+fn check_outcome(test: &str, xfails: &[XFail], xpass: &mut Vec<u64>, line: u64, r: std::thread::Result<()>) {
+    match (r, xfails.iter().find(|x| x.line == Some(line))) {
+        (Ok(()), None) => {}
+        (Ok(()), Some(_)) => xpass.push(line),
+        (Err(_), Some(xfail)) => println!("{test}:{line} XFAIL: {}", xfail.reason),
+        (Err(e), None) => std::panic::resume_unwind(e),
+    }
+}
+
+/// Run every command of a wast2json script, the outcome of each one is passed to `report` with its line and type
+fn run_commands(testset: &str, path: &str, check_rejections: bool, mut report: impl FnMut(u64, &'static str, std::thread::Result<()>)) {
+    let conf = PathBuf::from_str(path).unwrap();
+    let t: T = serde_json::from_str(&std::fs::read_to_string(&conf).unwrap_or_else(|_| panic!("Failed to find {}", conf.display()))).unwrap();
+
+    // Push the arguments and invoke an exported function
+    let call = |m: *mut Instance, field: &str, args: &[Arg]| -> SafeR {
+        for a in args {
+            unsafe {
+                let sp = m.as_mut().unwrap().sp + 1;
+                m.as_mut().unwrap().stack[sp as usize] = a.sv();
+                m.as_mut().unwrap().sp = sp;
+            }
+        }
+
+        let mut fs = field.as_bytes().to_vec();
+        fs.push(0);
+        let f = get_export_fidx(unsafe { (*m).module }, fs.as_ptr(), fs.len() as u32);
+        if f == u32::MAX {
+            panic!("Failed to find fidx: {:X?}", fs);
+        }
+        invoke(m, f).into_safe()
+    };
+
+    let global = |m: *mut Instance, field: &str| -> SV {
+        let mut fs = field.as_bytes().to_vec();
+        fs.push(0);
+        let exp = get_export(unsafe { (*m).module }, fs.as_ptr(), fs.len() as u32);
+        match unsafe { exp.as_ref() } {
+            Some(e) if e.kind == KIND_GLOBAL => unsafe { *(get_export_value(m, exp) as *const SV) },
+            _ => panic!("Failed to find global: {field}"),
+        }
+    };
 
     let mut mod_map = HashMap::new();
+    let mut linker = Box::new(Linker {
+        registered: HashMap::new(),
+        spectest: Spectest::new(),
+        linked: Vec::new(),
+        get_export,
+        invoke,
+    });
 
     let mut m = core::ptr::null_mut();
     for c in t.commands {
-        match c {
-            C::Module { filename, name } => {
+        let (line, kind) = (c.line(), c.kind());
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match c {
+            C::Module { filename, name, .. } => {
                 let mm = conf.parent().unwrap().join(filename);
                 println!("{:?}", mm);
                 let mm = std::fs::read(&mm).unwrap();
-                let mo = load_module(mm.as_ptr(), mm.len(), O {
-                    disable_memory_bounds: false,
-                    mangle_table_index: false,
-                    dlsym_trim_underscore: false,
-                });
+                let mo = unsafe {
+                    load_module(mm.as_ptr(), mm.len() as u32, O {
+                        resolve_import: Some(resolve_import),
+                        resolve_env: linker.as_mut() as *mut Linker as *mut libc::c_void,
+                        ..O::default()
+                    })
+                };
                 assert_ne!(mo, core::ptr::null_mut());
 
-                if let Some(name) = name {
-                    mod_map.insert(name, mo);
-                } else {
-                    m = mo;
+                let mut inst = core::ptr::null_mut();
+                match instantiate(mo, &mut inst).into_safe() {
+                    SafeR::Ok => {}
+                    e => panic!("{e:?}"),
                 }
 
-                // unsafe {
-                //     m.as_mut().unwrap().fp = 0;
-                //     let sp = m.as_mut().unwrap().sp+1;
-                //     m.as_mut().unwrap().stack[sp as usize] = SV {
-                //         value_ty: 0x7f,
-                //         v: SVV {
-                //             u32: 0x1234
-                //         }
-                //     };
-                //     m.as_mut().unwrap().sp = sp;
-                //
-                // }
+                if let Some(name) = name {
+                    mod_map.insert(name, inst);
+                }
+                m = inst;
             }
-            C::AssertReturn { action, expected, line } => {
-                match action {
+            // An action checks its results like assert_return, they are usually none
+            C::AssertReturn { action, expected, line } | C::Action { action, expected, line } => {
+                let (field, args, res) = match action {
                     A::Invoke { field, args, module } => {
                         println!("field {testset}:{field}::{line}");
 
@@ -466,46 +1028,8 @@ pub fn run_test(testset: &'static str) {
                             m
                         };
 
-                        // any error will leave the module in bad state
-                        //let m = snap(m);
-
-
-                        if field == "write" && line == 137 {
-                            println!("Skip multi");
-                            //snap_dest(m);
-                            continue;
-                        }
-                        // if field == "multi" {
-                        //     println!("Skip multi");
-                        //     continue;
-
-                        //print!("{testset}:{field}... ");
-
-                        if expected.len() > 1 {
-                            println!("field {testset}:{field}::{line} >2 vals what");
-                            //snap_dest(m);
-                            continue;
-                        }
-
-                        for a in &args {
-                            match a {
-                                _ =>
-                                unsafe {
-                                let sp = m.as_mut().unwrap().sp.wrapping_add(1);
-                                m.as_mut().unwrap().stack[sp as usize] = a.sv();
-                                m.as_mut().unwrap().sp = sp;
-                                }
-                            }
-                        }
-
-                        let mut fs = field.as_bytes().to_vec();
-                        fs.push(0);
-                        let f = get_export_fidx(m, fs.as_ptr(), fs.len() as u32);
-                        if f as i32 == -1 {
-                            panic!("Failed to find fidx: {:X?}", fs);
-                        }
-                        let r = invoke(m, f);
-                        match r.safe_r() {
+                        let base = unsafe { m.as_ref().unwrap().sp };
+                        match call(m, &field, &args) {
                             SafeR::Ok => {}
                             SafeR::Err(s) => panic!("{s}"),
                             SafeR::ErrNest(s1, s2) => {
@@ -518,125 +1042,220 @@ pub fn run_test(testset: &'static str) {
                             },
                         }
 
-                        if let Some(exp) = expected.first() {
-                            let res = unsafe {
-                                let sp = m.as_mut().unwrap().sp;
-                                let res = m.as_mut().unwrap().stack[sp as usize];
-                                m.as_mut().unwrap().sp = sp.wrapping_sub(1);
-                                res
-                            };
-
-                            let res_s = res.safe();
-                            let exp_s = exp.sv().safe();
-
-                            if  res_s != exp_s  {
-                                if let (SafeSV::F32(a), SafeSV::F32(b)) = (res_s, exp_s) {
-                                    if a.is_nan() && b.is_nan() {
-                                        //snap_dest(m);
-                                        continue;
-                                    }
-                                }
-
-                                if let (SafeSV::F64(a), SafeSV::F64(b)) = (res_s, exp_s) {
-                                    if a.is_nan() && b.is_nan() {
-                                        //snap_dest(m);
-                                        continue;
-                                    }
-                                }
+                        // The results are left on the stack above where the arguments were pushed
+                        let res = unsafe {
+                            let m = m.as_mut().unwrap();
+                            let first = (base + 1) as usize;
+                            let res = m.stack[first..first + (m.sp - base) as usize].to_vec();
+                            m.sp = base;
+                            res
+                        };
+                        (field, args, res)
+                    }
+                    A::Get { field, module } => {
+                        println!("field {testset}:{field}::{line}");
 
+                        let m = if let Some(module) = module {
+                            *mod_map.get(&module).unwrap()
+                        } else {
+                            m
+                        };
+                        let res = vec![global(m, &field)];
+                        (field, Vec::new(), res)
+                    }
+                };
 
-                                if let (SafeSV::F64(a), SafeSV::F64(b)) = (res_s, exp_s) {
-                                    let dif = (a - b).abs();
-                                    if dif < 20.0 {
-                                        println!("Allowing f64 with small diff {dif}");
-                                        //snap_dest(m);
-                                        continue;
-                                    }
-                                }
 
-                                if let (SafeSV::F32(a), SafeSV::F32(b)) = (res_s, exp_s) {
-                                    let dif = (a - b).abs();
-                                    if  dif < 20.0 {
-                                        println!("Allowing f64 with small diff {dif}");
-                                        //snap_dest(m);
-                                        continue;
-                                    }
-                                }
+                if res.len() != expected.len() {
+                    println!("field {testset}:{field}::{line} failed:");
+                    println!("args {args:?}");
+                    println!("res: {:?}", res.iter().map(SV::safe).collect::<Vec<_>>());
+                    panic!("returned {} values, expected {}", res.len(), expected.len());
+                }
 
-                                println!("field {testset}:{field}::{line} failed:");
-                                println!("args {args:?}");
-                                println!("res: {:?} / {:x?}", res.safe(), res.safe());
-                                println!("exp: {:?} / {:x?}", exp.sv().safe(), exp.sv().safe());
-                                unsafe { println!("exp/res: {:x?} / {:x?}", exp.sv().v.u64, res.v.u64); }
-                                panic!()
-                                //println!("{}", console::style("failed").red());
-                           } else {
-                                println!("field {testset}:{field}::{line} ok");
-                                //println!("{}", console::style("passed").green());
-                            }
-                        } else {
-                            println!("field {testset}:{field}::{line} not testing, no exp");
-                        }
-                        //snap_dest(m);
+                for (i, (res, exp)) in res.iter().zip(&expected).enumerate() {
+                    if !result_matches(res, exp) {
+                        println!("field {testset}:{field}::{line} failed:");
+                        println!("args {args:?}");
+                        println!("res[{i}]: {:?} / {:x?}", res.safe(), res.safe());
+                        println!("exp[{i}]: {:?} / {:x?}", exp.sv().safe(), exp.sv().safe());
+                        unsafe { println!("exp/res: {:x?} / {:x?}", exp.sv().v.u64, res.v.u64); }
+                        panic!("result {i} is {:?}, expected {:?}", res.safe(), exp.sv().safe())
                     }
-                    A::Get { .. } => {}
                 }
+                println!("field {testset}:{field}::{line} ok");
             }
-            C::AssertInvalid { .. } => {
-                // print!("{testset}:{field}... ");
-            }
-            C::AssertMalformed {..} => {
-                // print!("{testset}:{field}... ");
-            }
-            C::AssertTrap { .. } => {
-                // print!("{testset}:{field}... ");
+            C::AssertInvalid { filename, text, module_type, line } | C::AssertMalformed { filename, text, module_type, line } => {
+                // The tests check these separately in run_reject_test
+                if !check_rejections || module_type != "binary" {
+                    return;
+                }
+                check_rejected(testset, &conf.parent().unwrap().join(filename), &text, line);
             }
-            C::AssertExhaustion { .. } => {}
-            C::AssertUninstantiable { .. } => {}
-            C::Register { .. } => {}
-            C::Action { action, expected, line } => {
-                match action {
+            C::AssertTrap { action, line, text } | C::AssertExhaustion { action, line, text } => {
+                let (field, args, r) = match action {
                     A::Invoke { field, args, module } => {
                         println!("field {testset}:{field}::{line}");
 
-                        assert!(module.is_none());
-
-                        if !expected.is_empty() {
-                            panic!();
-                        }
+                        let m = if let Some(module) = module {
+                            *mod_map.get(&module).unwrap()
+                        } else {
+                            m
+                        };
 
-                        for a in &args {
-                            match a {
-                                _ =>
-                                    unsafe {
-                                        let sp = m.as_mut().unwrap().sp.wrapping_add(1);
-                                        m.as_mut().unwrap().stack[sp as usize] = a.sv();
-                                        m.as_mut().unwrap().sp = sp;
-                                    }
-                            }
-                        }
+                        let r = call(m, &field, &args);
+                        (field, args, r)
+                    }
+                    A::Get { field, module } => {
+                        println!("field {testset}:{field}::{line}");
 
-                        let mut fs = field.as_bytes().to_vec();
-                        fs.push(0);
-                        let f = get_export_fidx(m, fs.as_ptr(), fs.len() as u32);
-                        if f as i32 == -1 {
-                            panic!("Failed to find fidx: {:X?}", fs);
-                        }
-                        let r = invoke(m, f);
-                        match r.safe_r() {
-                            SafeR::Ok => {}
-                            SafeR::Err(s) => panic!("{s}"),
-                            SafeR::ErrNest(s1, s2) => panic!("{s1:?} {s2:?}"),
-                        }
+                        let m = if let Some(module) = module {
+                            *mod_map.get(&module).unwrap()
+                        } else {
+                            m
+                        };
+                        // Reading a global can't trap, this always fails
+                        global(m, &field);
+                        (field, Vec::new(), SafeR::Ok)
+                    }
+                };
+                match r.cause() {
+                    Some(msg) if msg.starts_with(&text) => {
+                        println!("field {testset}:{field}::{line} ok, trapped with \"{msg}\"");
+                    }
+                    Some(msg) => {
+                        println!("field {testset}:{field}::{line} failed:");
+                        println!("args {args:?}");
+                        panic!("trapped with \"{msg}\", expected \"{text}\"");
+                    }
+                    None => {
+                        println!("field {testset}:{field}::{line} failed:");
+                        println!("args {args:?}");
+                        panic!("returned normally, expected trap \"{text}\"");
                     }
-                    A::Get { .. } => {}
                 }
             }
-            C::AssertUnlinkable { .. } => {}
+            C::AssertUninstantiable { filename, text, line } | C::AssertUnlinkable { filename, text, line } => {
+                // The loader resolves imports and checks segment bounds, so either step can fail
+                println!("module {testset}:{filename}::{line}");
+                let mm = std::fs::read(conf.parent().unwrap().join(&filename)).unwrap();
+                let mut mo = core::ptr::null_mut();
+                let mut pos = 0;
+                let options = O {
+                    resolve_import: Some(resolve_import),
+                    resolve_env: linker.as_mut() as *mut Linker as *mut libc::c_void,
+                    ..O::default()
+                };
+                let r = match unsafe { load_module_checked(mm.as_ptr(), mm.len() as u32, options, &mut mo, &mut pos) }.into_safe() {
+                    SafeR::Ok => {
+                        // An instance whose start function traps is kept, registered tables can refer to it
+                        let mut inst = core::ptr::null_mut();
+                        instantiate(mo, &mut inst).into_safe()
+                    }
+                    e => e,
+                };
+                match r.cause() {
+                    Some(msg) if msg.starts_with(&text) => {
+                        println!("module {testset}:{filename}::{line} ok, failed with \"{msg}\"");
+                    }
+                    Some(msg) => {
+                        println!("module {testset}:{filename}::{line} failed:");
+                        panic!("failed with \"{msg}\", expected \"{text}\"");
+                    }
+                    None => {
+                        println!("module {testset}:{filename}::{line} failed:");
+                        panic!("linked and instantiated, expected \"{text}\"");
+                    }
+                }
+            }
+            C::Register { name, as_, .. } => {
+                let mo = if let Some(name) = name {
+                    *mod_map.get(&name).unwrap()
+                } else {
+                    m
+                };
+                linker.registered.insert(as_, mo);
+            }
+        }));
+
+        report(line, kind, r);
+    }
+}
+
+/// Compare a value left on the stack against an expected `assert_return` result
+fn result_matches(res: &SV, exp: &Arg) -> bool {
+    match exp {
+        Arg::F32 { value } if value.starts_with("nan:") => {
+            if res.value_ty != 0x7d {
+                return false;
+            }
+            let bits = unsafe { res.v.u32 };
+            match value.as_str() {
+                "nan:canonical" => bits & 0x7fffffff == 0x7fc00000,
+                "nan:arithmetic" => bits & 0x7fc00000 == 0x7fc00000,
+                _ => panic!("Unknown NaN kind {value}"),
+            }
+        }
+        Arg::F64 { value } if value.starts_with("nan:") => {
+            if res.value_ty != 0x7c {
+                return false;
+            }
+            let bits = unsafe { res.v.u64 };
+            match value.as_str() {
+                "nan:canonical" => bits & 0x7fffffffffffffff == 0x7ff8000000000000,
+                "nan:arithmetic" => bits & 0x7ff8000000000000 == 0x7ff8000000000000,
+                _ => panic!("Unknown NaN kind {value}"),
+            }
+        }
+        _ => *res == exp.sv(),
+    }
+}
+
+/// Check that loading a binary module fails with the reason the spec gives, panicking if it loads
+/// or is rejected for something else
+fn check_rejected(testset: &str, path: &std::path::Path, text: &str, line: u64) {
+    let filename = path.file_name().unwrap().to_string_lossy();
+    println!("module {testset}:{filename}::{line}");
+
+    let mm = std::fs::read(path).unwrap();
+    match load_rejected(&mm) {
+        Ok(reason) if reason.starts_with(text) => {
+            println!("module {testset}:{filename}::{line} ok, rejected with \"{reason}\"");
+        }
+        Ok(reason) => {
+            println!("module {testset}:{filename}::{line} failed:");
+            panic!("rejected with \"{reason}\", expected \"{text}\"");
+        }
+        Err(e) => {
+            println!("module {testset}:{filename}::{line} failed:");
+            panic!("{e}, expected rejection \"{text}\"");
         }
     }
 }
 
-// todos:
-// fix all tests
-// add missing thunks for tests names:print32::1107
\ No newline at end of file
+/// Check that every module the spec expects to be invalid or malformed fails to load
+pub fn run_reject_test(testset: &'static str) {
+    let test = format!("reject_test::{testset}");
+    let xfails = known_failures().into_iter().filter(|x| x.test == test && x.line.is_some()).collect::<Vec<_>>();
+    let mut xpass = Vec::new();
+
+    let conf = PathBuf::from_str(&format!("res/wg-1.0/{testset}.wast_/{testset}.wast.json")).unwrap();
+    let t: T = serde_json::from_str(&std::fs::read_to_string(&conf).unwrap_or_else(|_| panic!("Failed to find {}", conf.display()))).unwrap();
+
+    for c in t.commands {
+        match c {
+        C::AssertInvalid { filename, text, module_type, line } | C::AssertMalformed { filename, text, module_type, line } => {
+            // Text modules are only malformed in their .wat form, there is nothing to load
+            if module_type != "binary" {
+                continue;
+            }
+            let path = conf.parent().unwrap().join(&filename);
+            let r = std::panic::catch_unwind(|| check_rejected(testset, &path, &text, line));
+            check_outcome(&test, &xfails, &mut xpass, line, r);
+        }
+            _ => {}
+        }
+    }
+
+    assert!(xpass.is_empty(), "{test} XPASS at lines {xpass:?}, remove them from xfail.txt");
+}
@@ -9,9 +9,9 @@
  Outbound Thunks (calling imported functions)
 */
 
-result_t thunk_out(Module* m, uint32_t fidx) {
+result_t thunk_out(Instance* m, uint32_t fidx) {
     int p;
-    Block* func = &m->functions[fidx];
+    Block* func = &m->module->functions[fidx];
     Type* type = func->type;
     if (should_trace()) {
         wa_warn("  >>> thunk_out 0x%x(%d) %s.%s(", func->fidx, func->fidx,
@@ -23,10 +23,14 @@ result_t thunk_out(Module* m, uint32_t fidx) {
         wa_debug("      mask: 0x%llx\n", type->mask);
     }
 
+    if (func->host_func) {
+        return func->host_func(m, type, func->host_env);
+    }
+
     if (!func->func_ptr) {
         char* msg = calloc(1024 + strlen(func->import_field), 1);
         sprintf(msg, "No thunk for %s\n", func->import_field);
-        return res_new_err(msg);
+        return res_new_err_owned(msg);
     }
 
     switch (type->mask) {
@@ -115,7 +119,7 @@ result_t thunk_out(Module* m, uint32_t fidx) {
  This global is used by setup_thunk_in since signal handlers don't have
  a way to pass arguments when they are setup.
  */
-Module* _wa_current_module_;
+Instance* _wa_current_instance_;
 
 THUNK_IN_FN_0(m, 0)
 THUNK_IN_FN_2(m, 0, i, i)
@@ -124,13 +128,14 @@ THUNK_IN_FN_1(m, i, i)
 THUNK_IN_FN_2(m, i, i, i)
 
 /* Push arguments
- return function pointer to thunk_in_* function
+ return function pointer to thunk_in_* function, NULL if the stack can't hold
+ the call
  */
 void (*setup_thunk_in(uint32_t fidx))(void) {
     uint32_t p;
     void (*f)(void) = NULL;
-    Module* m = _wa_current_module_; /* TODO: global state, clean up somehow */
-    Block* func = &m->functions[fidx];
+    Instance* m = _wa_current_instance_; /* TODO: global state, clean up */
+    Block* func = &m->module->functions[fidx];
     Type* type = func->type;
 
     /* Make space on the stack*/
@@ -142,7 +147,11 @@ void (*setup_thunk_in(uint32_t fidx))(void) {
 
     /* Do normal function call setup. The fp will point to the start of stack
      elements that were just added above*/
-    setup_call(m, fidx);
+    if (res_err(setup_call(m, fidx))) {
+        wa_error("call stack exhausted calling 0x%x\n", fidx);
+        m->sp -= type->param_count;
+        return NULL;
+    }
 
     /* Set the type of the unset stack elements */
     for (p = 0; p < type->param_count; p++) {
@@ -167,6 +176,6 @@ void (*setup_thunk_in(uint32_t fidx))(void) {
     return f;
 }
 
-void init_thunk_in(Module* m) {
-    _wa_current_module_ = m; /* TODO: global state, clean up somehow*/
+void init_thunk_in(Instance* m) {
+    _wa_current_instance_ = m; /* TODO: global state, clean up somehow*/
 }
@@ -97,22 +97,22 @@
         TH_OUT_RET_##R(M);                                              \
     }
 
-#define THUNK_IN_FN_0(M, R)              \
-    TH_C_##R thunk_in_##R##_0(void) {    \
-        Module* m = _wa_current_module_; \
-        interpret(M);                    \
-        return TH_IN_RET_##R(M);         \
+#define THUNK_IN_FN_0(M, R)                  \
+    TH_C_##R thunk_in_##R##_0(void) {        \
+        Instance* m = _wa_current_instance_; \
+        interpret(M);                        \
+        return TH_IN_RET_##R(M);             \
     }
 #define THUNK_IN_FN_1(M, R, A)                \
     TH_C_##R thunk_in_##R##_##A(TH_C_##A a) { \
-        Module* m = _wa_current_module_;      \
+        Instance* m = _wa_current_instance_;  \
         TH_FP(M, +0, A) = a;                  \
         interpret(M);                         \
         return TH_IN_RET_##R(M);              \
     }
 #define THUNK_IN_FN_2(M, R, A, B)                            \
     TH_C_##R thunk_in_##R##_##A##B(TH_C_##A a, TH_C_##B b) { \
-        Module* m = _wa_current_module_;                     \
+        Instance* m = _wa_current_instance_;                 \
         TH_FP(M, +0, A) = a;                                 \
         TH_FP(M, +1, B) = b;                                 \
         interpret(M);                                        \
@@ -120,7 +120,7 @@
     }
 #define THUNK_IN_FN_3(M, R, A, B, C)                                        \
     TH_C_##R thunk_in_##R##_##A##B##C(TH_C_##A a, TH_C_##B b, TH_C_##C c) { \
-        Module* m = _wa_current_module_;                                    \
+        Instance* m = _wa_current_instance_;                                \
         TH_FP(M, +0, A) = a;                                                \
         TH_FP(M, +1, B) = b;                                                \
         TH_FP(M, +2, C) = c;                                                \
@@ -132,9 +132,9 @@
 extern "C" {
 #endif
 
-result_t thunk_out(Module* m, uint32_t fidx);
+result_t thunk_out(Instance* m, uint32_t fidx);
 
-void init_thunk_in(Module* m);
+void init_thunk_in(Instance* m);
 
 #ifdef __cplusplus
 }
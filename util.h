@@ -30,6 +30,9 @@
   }
 #endif
 
+/* 0 logs nothing, 1 errors, 2 warnings, 3 info, 4 debug, 5 traces */
+void wa_set_log_level(int ll);
+
 void wa_debug(char *c, ...);
 void wa_info(char *c, ...);
 void wa_warn(char *c, ...);
@@ -1,4 +1,6 @@
 #include <math.h>
+#include <setjmp.h>
+#include <stdarg.h>
 #include <stdlib.h>
 #include <string.h>
 #include "fixes.h"
@@ -53,20 +55,21 @@ static int _signbit(double x) {
     return u.u64 >> 63;
 }
 
+/* Arithmetic on a signalling NaN quiets it, as the spec requires */
+static double quiet(double x) {
+    return wa_isnan(x) ? x + x : x;
+}
+
 static double wa_fmax(double a, double b) {
-    if (wa_isnan(a))
-        return a;
-    if (wa_isnan(b))
-        return b;
+    if (wa_isnan(a) || wa_isnan(b))
+        return a + b;
     if (_signbit(a) != _signbit(b))
         return _signbit(a) ? b : a;
     return a > b ? a : b;
 }
 static double wa_fmin(double a, double b) {
-    if (wa_isnan(a))
-        return a;
-    if (wa_isnan(b))
-        return b;
+    if (wa_isnan(a) || wa_isnan(b))
+        return a + b;
     if (_signbit(a) != _signbit(b))
         return _signbit(a) ? a : b;
 
@@ -152,7 +155,7 @@ static double wa_rint(double x) {
         double d;
         uint64_t u64;
     } u;
-    uint64_t a;
+    uint64_t a, sign;
 
     u.d = x;
     a = u.u64 & 0x7fffffffffffffffULL;
@@ -161,67 +164,288 @@ static double wa_rint(double x) {
         return x;
     }
 
-    u.u64 = (u.u64 & 0x8000000000000000ULL) | 0x4330000000000000ULL;
+    sign = u.u64 & 0x8000000000000000ULL;
+    u.u64 = sign | 0x4330000000000000ULL;
 
     x += u.d;
     x -= u.d;
 
-    return x;
+    /* Values that round to zero keep their sign */
+    u.d = x;
+    u.u64 |= sign;
+    return u.d;
 }
 
 /* type readers */
 
-static uint64_t read_LEB_(uint8_t* bytes,
-                          uint32_t* pos,
-                          uint32_t maxbits,
-                          bool sign) {
+/* Decodes the LEB128 at pos, which must end before end. Returns NULL or why
+ * it is malformed */
+static char* decode_LEB(const uint8_t* bytes,
+                        uint32_t end,
+                        uint32_t* pos,
+                        uint32_t maxbits,
+                        bool sign,
+                        uint64_t* out) {
     uint64_t result = 0;
     uint32_t shift = 0;
     uint32_t bcnt = 0;
-    uint32_t startpos = *pos;
-    uint64_t byte;
+    uint32_t max_bytes = (maxbits + 7 - 1) / 7;
+    uint32_t last_bits = maxbits % 7;
+    uint64_t byte, mask;
 
     while (true) {
+        if (*pos >= end) {
+            return "unexpected end of section or function";
+        }
         byte = bytes[*pos];
         *pos += 1;
+        bcnt += 1;
         result |= ((byte & 0x7f) << shift);
         shift += 7;
         if ((byte & 0x80) == 0) {
             break;
         }
-        bcnt += 1;
-        if (bcnt > (maxbits + 7 - 1) / 7) {
-            FATAL("Unsigned LEB at byte %d overflow", startpos);
+        if (bcnt == max_bytes) {
+            return "integer representation too long";
+        }
+    }
+    if (bcnt == max_bytes && last_bits != 0) {
+        /* The bits of the last byte past maxbits are padding, zeros or for a
+         * signed value copies of the sign bit */
+        if (sign) {
+            mask = 0x7f & ~((1 << (last_bits - 1)) - 1);
+            if ((byte & mask) != 0 && (byte & mask) != mask) {
+                return "integer too large";
+            }
+        } else if ((byte & (0x7f & ~((1 << last_bits) - 1))) != 0) {
+            return "integer too large";
         }
     }
-    if (sign && (shift < maxbits) && (byte & 0x40)) {
+    if (sign && shift < 64 && (byte & 0x40)) {
         /* Sign extend */
-        result |= -(1 << shift);
+        result |= ~(uint64_t)0 << shift;
+    }
+    *out = result;
+    return NULL;
+}
+
+/* Reads validated code, the end of a LEB128 is always found */
+static uint64_t read_LEB_(const uint8_t* bytes,
+                          uint32_t* pos,
+                          uint32_t maxbits,
+                          bool sign) {
+    uint64_t result;
+    uint32_t startpos = *pos;
+    char* err = decode_LEB(bytes, (uint32_t)-1, pos, maxbits, sign, &result);
+
+    if (err) {
+        FATAL("LEB at byte %d: %s", startpos, err);
     }
     return result;
 }
 
-static uint64_t read_LEB(uint8_t* bytes, uint32_t* pos, uint32_t maxbits) {
+static uint64_t read_LEB(const uint8_t* bytes,
+                         uint32_t* pos,
+                         uint32_t maxbits) {
     return read_LEB_(bytes, pos, maxbits, false);
 }
 
-static uint64_t read_LEB_signed(uint8_t* bytes,
+static uint64_t read_LEB_signed(const uint8_t* bytes,
                                 uint32_t* pos,
                                 uint32_t maxbits) {
     return read_LEB_(bytes, pos, maxbits, true);
 }
 
-static uint32_t read_uint32(uint8_t* bytes, uint32_t* pos) {
+/* Little endian, bytewise as an execute in place module can be unaligned */
+static uint32_t read_uint32(const uint8_t* bytes, uint32_t* pos) {
+    const uint8_t* b = bytes + *pos;
+
     *pos += 4;
-    return ((uint32_t*)(bytes + *pos - 4))[0];
+    return (uint32_t)b[0] | (uint32_t)b[1] << 8 | (uint32_t)b[2] << 16 |
+           (uint32_t)b[3] << 24;
 }
 
-/* Reads a string from the bytes array at pos that starts with a LEB length
- if result_len is not NULL, then it will be set to the string length*/
-static char* read_string(uint8_t* bytes, uint32_t* pos, uint32_t* result_len) {
-    uint32_t str_len = read_LEB(bytes, pos, 32);
-    char* str = (char*)calloc(str_len + 1, 1);
-    memcpy(str, bytes + *pos, str_len);
+/* A module being loaded. Loading stops at the first error, load_fail records
+ * where and why and jumps back to load_module_checked */
+typedef struct Loader {
+    Module* m;
+    jmp_buf fail;
+    uint32_t error_pos;
+    char error[256];
+    uint32_t end; /* reads stop here, the end of the section or function */
+
+    /* What validation needs that the module doesn't keep */
+    uint32_t table_count;
+    uint32_t memory_count;
+
+    /* Names of the import being read, until the module owns or frees them */
+    char* import_module;
+    char* import_field;
+
+    /* Operand and control stacks of the function being validated */
+    uint8_t* vals;
+    uint32_t val_count, val_cap, val_max;
+    struct Control* ctrls;
+    uint32_t ctrl_count, ctrl_cap;
+
+    uint32_t block_cap; /* room in module->blocks */
+
+    /* The first import that couldn't be resolved. Like the spec, it fails
+     * to link once the module is known to be valid */
+    uint32_t unknown_import_pos;
+    char unknown_import[160];
+} Loader;
+
+/* Records an import that couldn't be resolved, loading goes on without it */
+static void load_unknown_import(Loader* l,
+                                uint32_t pos,
+                                const char* module,
+                                const char* field) {
+    if (l->unknown_import[0] == '\0') {
+        l->unknown_import_pos = pos;
+        sprintf(l->unknown_import, "unknown import %.64s.%.64s", module,
+                field);
+    }
+}
+
+/* Only bounded formats, strings from the module must have a precision */
+static void load_fail(Loader* l, uint32_t pos, char* fmt, ...) {
+    va_list ap;
+
+    l->error_pos = pos;
+    va_start(ap, fmt);
+    vsprintf(l->error, fmt, ap);
+    va_end(ap);
+    longjmp(l->fail, 1);
+}
+
+static void* load_calloc(Loader* l,
+                         uint32_t pos,
+                         size_t nmemb,
+                         size_t size,
+                         char* name) {
+    void* res = calloc(nmemb, size);
+    if (res == NULL && nmemb * size != 0) {
+        load_fail(l, pos, "could not allocate %lu bytes for %s",
+                  (unsigned long)(nmemb * size), name);
+    }
+    return res;
+}
+
+static void* load_recalloc(Loader* l,
+                           uint32_t pos,
+                           void* ptr,
+                           size_t old_nmemb,
+                           size_t nmemb,
+                           size_t size,
+                           char* name) {
+    void* res = realloc(ptr, nmemb * size);
+    if (res == NULL) {
+        load_fail(l, pos, "could not allocate %lu bytes for %s",
+                  (unsigned long)(nmemb * size), name);
+    }
+    memset((uint8_t*)res + old_nmemb * size, 0, (nmemb - old_nmemb) * size);
+    return res;
+}
+
+/* Fails unless count bytes can be read at pos */
+static void load_need(Loader* l, uint32_t pos, uint32_t count) {
+    if (pos > l->end || count > l->end - pos) {
+        load_fail(l, pos, "unexpected end of section or function");
+    }
+}
+
+static uint8_t load_byte(Loader* l, uint32_t* pos) {
+    load_need(l, *pos, 1);
+    *pos += 1;
+    return l->m->bytes[*pos - 1];
+}
+
+static uint64_t load_LEB_(Loader* l,
+                          uint32_t* pos,
+                          uint32_t maxbits,
+                          bool sign) {
+    uint64_t result;
+    uint32_t startpos = *pos;
+    /* A LEB128 running past the end is malformed in itself first, so it's
+     * decoded up to the end of the module */
+    char* err =
+        decode_LEB(l->m->bytes, l->m->byte_count, pos, maxbits, sign, &result);
+
+    if (err) {
+        load_fail(l, startpos, "%s", err);
+    }
+    if (*pos > l->end) {
+        load_fail(l, startpos, "unexpected end of section or function");
+    }
+    return result;
+}
+
+static uint64_t load_LEB(Loader* l, uint32_t* pos, uint32_t maxbits) {
+    return load_LEB_(l, pos, maxbits, false);
+}
+
+static uint64_t load_LEB_signed(Loader* l, uint32_t* pos, uint32_t maxbits) {
+    return load_LEB_(l, pos, maxbits, true);
+}
+
+/* Reads the count of a vector, whose items take at least a byte each */
+static uint32_t load_count(Loader* l, uint32_t* pos) {
+    uint32_t start = *pos;
+    uint32_t count = load_LEB(l, pos, 32);
+
+    if (count > l->end - *pos) {
+        load_fail(l, start, "unexpected end of section or function");
+    }
+    return count;
+}
+
+/* Whether the len bytes at s are well-formed UTF-8, without overlong
+ * encodings, surrogates or code points past 0x10ffff */
+static bool is_utf8(const uint8_t* s, uint32_t len) {
+    uint32_t i = 0, n, k, cp, min;
+
+    while (i < len) {
+        if (s[i] < 0x80) {
+            i++;
+            continue;
+        } else if ((s[i] & 0xe0) == 0xc0) {
+            n = 1, cp = s[i] & 0x1f, min = 0x80;
+        } else if ((s[i] & 0xf0) == 0xe0) {
+            n = 2, cp = s[i] & 0x0f, min = 0x800;
+        } else if ((s[i] & 0xf8) == 0xf0) {
+            n = 3, cp = s[i] & 0x07, min = 0x10000;
+        } else {
+            return false;
+        }
+        if (n > len - i - 1) {
+            return false;
+        }
+        for (k = 1; k <= n; k++) {
+            if ((s[i + k] & 0xc0) != 0x80) {
+                return false;
+            }
+            cp = cp << 6 | (s[i + k] & 0x3f);
+        }
+        if (cp < min || cp > 0x10ffff || (cp >= 0xd800 && cp <= 0xdfff)) {
+            return false;
+        }
+        i += n + 1;
+    }
+    return true;
+}
+
+static char* load_string(Loader* l, uint32_t* pos, uint32_t* result_len) {
+    uint32_t str_len = load_LEB(l, pos, 32);
+    char* str;
+
+    /* Checked first, the length may be anything */
+    load_need(l, *pos, str_len);
+    if (!is_utf8(l->m->bytes + *pos, str_len)) {
+        load_fail(l, *pos, "invalid UTF-8 encoding");
+    }
+    str = load_calloc(l, *pos, str_len + 1, 1, "string");
+    memcpy(str, l->m->bytes + *pos, str_len);
     str[str_len] = '\0';
     *pos += str_len;
     if (result_len) {
@@ -471,7 +695,11 @@ static Type block_types[5] = {{BLOCK, 0, 0, 0, 0, 0},
                               {BLOCK, 0, 0, 1, block_type_results[2], 0},
                               {BLOCK, 0, 0, 1, block_type_results[3], 0}};
 
-static Type* get_block_type(Module* m, uint8_t value_type) {
+/* The type of the block whose block type is at pos, failing the load if it
+ * isn't a valid one */
+static Type* get_block_type(Loader* l, Module* m, uint32_t pos) {
+    uint8_t value_type = m->bytes[pos];
+
     switch (value_type) {
         case 0x40:
             return &block_types[0];
@@ -486,8 +714,7 @@ static Type* get_block_type(Module* m, uint8_t value_type) {
         case 0x70: /* ref type */
         case 0x6F: /* ext ref */
         case 0x60: /* func*/
-            FATAL("invalid block_type value_type: %d\n", value_type);
-            return NULL;
+            break;
         case 0x7A: /* Reserve*/
         case 0x79:
         case 0x78:
@@ -498,8 +725,7 @@ static Type* get_block_type(Module* m, uint8_t value_type) {
         case 0x73:
         case 0x72:
         case 0x71:
-            FATAL("reserved block_type value_type: %d\n", value_type);
-            return NULL;
+            break;
         case 0x5F:
         case 0x5E:
         case 0x5D:
@@ -531,11 +757,14 @@ static Type* get_block_type(Module* m, uint8_t value_type) {
         case 0x43:
         case 0x42:
         case 0x41:
-            FATAL("reserved block_type value_type: %d\n", value_type);
-            return NULL;
+            break;
         default:
-            return &m->types[value_type];
+            if (value_type < m->type_count) {
+                return &m->types[value_type];
+            }
     }
+    load_fail(l, pos, "invalid block type 0x%x", value_type);
+    return NULL;
 }
 
 /* TODO: calculate this while parsing types */
@@ -543,12 +772,9 @@ static uint64_t get_type_mask(Type* type) {
     uint32_t p;
     uint64_t mask = 0x80;
 
+    /* At most one result, load_module rejects more */
     if (type->result_count == 1) {
         mask |= 0x80 - type->results[0];
-    } else if (type->result_count == 3) {
-        mask |= 0x80 - type->results[0] - type->results[1] - type->results[2];
-    } else {
-        wa_warn("ERR GTM\n");
     }
     mask = mask << 4;
     for (p = 0; p < type->param_count; p++) {
@@ -558,6 +784,30 @@ static uint64_t get_type_mask(Type* type) {
     return mask;
 }
 
+/* Function types are equal if their params and results are */
+static bool types_equal(Type* a, Type* b) {
+    uint32_t i;
+
+    if (a == b) {
+        return true;
+    }
+    if (a->param_count != b->param_count ||
+        a->result_count != b->result_count) {
+        return false;
+    }
+    for (i = 0; i < a->param_count; i++) {
+        if (a->params[i] != b->params[i]) {
+            return false;
+        }
+    }
+    for (i = 0; i < a->result_count; i++) {
+        if (a->results[i] != b->results[i]) {
+            return false;
+        }
+    }
+    return true;
+}
+
 /* FIXME: waisting 256Bytes! */
 char _value_str[256];
 char* value_repr(StackValue* v) {
@@ -598,7 +848,7 @@ static char* block_repr(Block* b) {
     return _block_str;
 }
 
-static void dump_stacks(Module* m) {
+static void dump_stacks(Instance* m) {
     int i;
     wa_warn("      * stack:     [");
     for (i = 0; i <= m->sp; i++) {
@@ -625,50 +875,79 @@ static void dump_stacks(Module* m) {
     wa_warn("]\n");
 }
 
-static void parse_table_type(Module* m, uint32_t* pos) {
+/* Returns whether the table has a maximum */
+static bool parse_table_type(Loader* l, uint32_t* pos) {
+    Module* m = l->m;
     uint32_t flags;
     uint32_t tsize;
-    m->table.elem_type = read_LEB(m->bytes, pos, 7);
-    ASSERT(m->table.elem_type == ANYFUNC, "Table elem_type 0x%x unsupported",
-           m->table.elem_type);
-    flags = read_LEB(m->bytes, pos, 32);
-    tsize = read_LEB(m->bytes, pos, 32); /* Initial size */
+    uint32_t start = *pos;
+    m->table.elem_type = load_LEB(l, pos, 7);
+    if (m->table.elem_type != ANYFUNC) {
+        load_fail(l, start, "table elem_type 0x%x unsupported",
+                  m->table.elem_type);
+    }
+    flags = load_LEB(l, pos, 32);
+    tsize = load_LEB(l, pos, 32); /* Initial size */
     m->table.initial = tsize;
     m->table.size = tsize;
     /* Limit maximum to 64K */
     if (flags & 0x1) {
-        tsize = read_LEB(m->bytes, pos, 32); /* Max size */
+        tsize = load_LEB(l, pos, 32); /* Max size */
+        if (tsize < m->table.initial) {
+            load_fail(l, start,
+                      "size minimum must not be greater than maximum");
+        }
         m->table.maximum = 0x10000 < tsize ? 0x10000 : tsize;
     } else {
         m->table.maximum = 0x10000;
     }
     wa_debug("  table size: %d\n", tsize);
+    return flags & 0x1;
 }
 
-static void parse_memory_type(Module* m, uint32_t* pos) {
-    uint32_t flags = read_LEB(m->bytes, pos, 32);
-    uint32_t pages = read_LEB(m->bytes, pos, 32); /* Initial size */
+/* Returns whether the memory has a maximum */
+static bool parse_memory_type(Loader* l, uint32_t* pos) {
+    Module* m = l->m;
+    uint32_t start = *pos;
+    uint32_t flags = load_LEB(l, pos, 32);
+    uint32_t pages = load_LEB(l, pos, 32); /* Initial size */
+    if (pages > 0x10000) {
+        load_fail(l, start, "memory size must be at most 65536 pages (4GiB)");
+    }
+    if (pages > 0x8000) {
+        load_fail(l, start, "initial memory of %u pages unsupported", pages);
+    }
     m->memory.initial = pages;
     m->memory.pages = pages;
     /* Limit the maximum to 2GB */
     if (flags & 0x1) {
-        pages = read_LEB(m->bytes, pos, 32); /* Max size */
+        pages = load_LEB(l, pos, 32); /* Max size */
+        if (pages > 0x10000) {
+            load_fail(l, start,
+                      "memory size must be at most 65536 pages (4GiB)");
+        }
+        if (pages < m->memory.initial) {
+            load_fail(l, start,
+                      "size minimum must not be greater than maximum");
+        }
         m->memory.maximum = (uint32_t)fmin(0x8000, pages);
     } else {
         m->memory.maximum = 0x8000;
     }
+    return flags & 0x1;
 }
 
-static void skip_immediates(uint8_t* bytes, uint32_t* pos) {
+static void skip_immediates(Loader* l, uint32_t* pos) {
     uint32_t i;
 
-    uint32_t count, opcode = bytes[*pos];
-    *pos = *pos + 1;
+    uint32_t count, opcode = load_byte(l, pos);
     switch (opcode) {
         /* varuint1 */
         case 0x3f:
         case 0x40: /* current_memory, grow_memory */
-            read_LEB(bytes, pos, 1);
+            if (load_byte(l, pos) != 0x00) {
+                load_fail(l, *pos - 1, "zero flag expected");
+            }
             break;
         /* varuint32, varint32 */
         case 0x0c:
@@ -679,31 +958,37 @@ static void skip_immediates(uint8_t* bytes, uint32_t* pos) {
         case 0x22: /* get/set_local, tee_local, get/set_global */
         case 0x23: /* get/set_local, tee_local, get/set_global */
         case 0x24: /* get/set_local, tee_local, get/set_global */
+            load_LEB(l, pos, 32);
+            break;
         case 0x41: /* i32.const */
-            read_LEB(bytes, pos, 32);
+            load_LEB_signed(l, pos, 32);
             break;
-        /* varuint32 + varuint1 */
+        /* varuint32 + reserved byte */
         case 0x11: /* call_indirect */
-            read_LEB(bytes, pos, 1);
-            read_LEB(bytes, pos, 32);
+            load_LEB(l, pos, 32);
+            if (load_byte(l, pos) != 0x00) {
+                load_fail(l, *pos - 1, "zero flag expected");
+            }
             break;
         /* varint64 */
         case 0x42: /* i64.const */
-            read_LEB(bytes, pos, 64);
+            load_LEB_signed(l, pos, 64);
             break;
         /* uint32 */
         case 0x43: /* f32.const */
+            load_need(l, *pos, 4);
             *pos += 4;
             break;
         /* uint64 */
         case 0x44: /* f64.const */
+            load_need(l, *pos, 8);
             *pos += 8;
             break;
         /* block_type */
         case 0x02:
         case 3:
         case 0x04: /* block, loop, if */
-            read_LEB(bytes, pos, 7);
+            load_LEB(l, pos, 7);
             break;
             /* memory_immediate */
         case 0x28:
@@ -729,85 +1014,538 @@ static void skip_immediates(uint8_t* bytes, uint32_t* pos) {
         case 0x3c:
         case 0x3d:
         case 0x3e:
-            read_LEB(bytes, pos, 32);
-            read_LEB(bytes, pos, 32);
+            load_LEB(l, pos, 32);
+            load_LEB(l, pos, 32);
             break;
         /* br_table */
         case 0x0e:                            /* br_table */
-            count = read_LEB(bytes, pos, 32); /* target count */
+            count = load_LEB(l, pos, 32); /* target count */
             for (i = 0; i < count; i++) {
-                read_LEB(bytes, pos, 32);
+                load_LEB(l, pos, 32);
             }
-            read_LEB(bytes, pos, 32); /* default target */
+            load_LEB(l, pos, 32); /* default target */
             break;
         default: /* no immediates */
             break;
     }
 }
 
-static void find_blocks(Module* m) {
-    Block* function;
+/* Decodes the code of a function, the blocks, loops and ifs it finds go to
+ * the module */
+static void find_blocks(Loader* l, Block* function) {
+    Module* m = l->m;
     Block* block;
-    Block* blockstack[BLOCKSTACK_SIZE];
+    uint32_t blockstack[BLOCKSTACK_SIZE]; /* indexes into m->blocks */
     int top = -1;
-    uint8_t opcode = 0x00;
-    uint32_t f, pos;
-    wa_info("  find_blocks: function_count: %d\n", m->function_count);
-    for (f = m->import_count; f < m->function_count; f++) {
-        function = &m->functions[f];
-        wa_debug("    fidx: 0x%x, start: 0x%x, end: 0x%x\n", f,
-                 function->start_addr, function->end_addr);
-        pos = function->start_addr;
-        while (pos <= function->end_addr) {
-            opcode = m->bytes[pos];
-            switch (opcode) {
-                case 0x02: /* block */
-                case 0x03: /* loop */
-                case 0x04: /* if */
-                    block = acalloc(1, sizeof(Block), "Block");
-                    block->block_type = opcode;
-                    block->type = get_block_type(m, m->bytes[pos + 1]);
-                    block->start_addr = pos;
-                    blockstack[++top] = block;
-                    m->block_lookup[pos] = block;
-                    break;
-                case 0x05: /* else */
-                    ASSERT(blockstack[top]->block_type == 0x04,
-                           "else not matched with if")
-                    blockstack[top]->else_addr = pos + 1;
+    uint8_t opcode;
+    uint32_t pos = function->start_addr;
+    bool ended;
+
+    wa_debug("    fidx: 0x%x, start: 0x%x, end: 0x%x\n", function->fidx,
+             function->start_addr, function->end_addr);
+    l->end = function->end_addr + 1;
+    ended = false;
+    while (!ended && pos <= function->end_addr) {
+        opcode = m->bytes[pos];
+        switch (opcode) {
+            case 0x02: /* block */
+            case 0x03: /* loop */
+            case 0x04: /* if */
+                if (top + 1 >= BLOCKSTACK_SIZE) {
+                    load_fail(l, pos, "blocks nested too deeply");
+                }
+                load_need(l, pos, 2);
+                /* Found in code order, so the blocks stay sorted */
+                if (m->block_count == l->block_cap) {
+                    l->block_cap = l->block_cap ? l->block_cap * 2 : 16;
+                    m->blocks = load_recalloc(l, pos, m->blocks,
+                                              m->block_count,
+                                              l->block_cap, sizeof(Block),
+                                              "Module->blocks");
+                }
+                block = &m->blocks[m->block_count];
+                block->block_type = opcode;
+                block->type = get_block_type(l, m, pos + 1);
+                block->start_addr = pos;
+                blockstack[++top] = m->block_count++;
+                break;
+            case 0x05: /* else */
+                if (top < 0 ||
+                    m->blocks[blockstack[top]].block_type != 0x04) {
+                    load_fail(l, pos, "else not matched with if");
+                }
+                m->blocks[blockstack[top]].else_addr = pos + 1;
+                break;
+            case 0x0b: /* end */
+                if (top < 0) {
+                    /* The function's own end */
+                    ended = true;
                     break;
-                case 0x0b: /* end */
-                    if (pos == function->end_addr) {
-                        break;
+                }
+                block = &m->blocks[blockstack[top--]];
+                block->end_addr = pos;
+                if (block->block_type == 0x03) {
+                    /* loop: label after start */
+                    block->br_addr = block->start_addr + 2;
+                } else {
+                    /* block, if: label at end */
+                    block->br_addr = pos;
+                }
+                wa_debug(
+                    "      block start: 0x%x, end: 0x%x,"
+                    " br_addr: 0x%x, else_addr: 0x%x\n",
+                    block->start_addr, block->end_addr, block->br_addr,
+                    block->else_addr);
+                break;
+        }
+        skip_immediates(l, &pos);
+    }
+
+    if (!ended) {
+        load_fail(l, l->end, "unexpected end of section or function");
+    }
+    if (pos != l->end) {
+        load_fail(l, pos, "operators remaining after the end of the function");
+    }
+}
+
+/* Validation of function bodies, following the algorithm in the appendix of
+ * the spec */
+
+/* The type of an operand in unreachable code, matching any type */
+#define UNKNOWN 0
+
+typedef struct Control {
+    uint8_t opcode;   /* block, loop, if, else, or 0x00 for the function */
+    uint8_t result;   /* BLOCK or the value type of the result */
+    uint32_t height;  /* Operand count at the start of the block */
+    bool unreachable; /* Only reached through a branch */
+} Control;
+
+static bool is_value_type(uint32_t type) {
+    return type == I32 || type == I64 || type == F32 || type == F64;
+}
+
+static void push_val(Loader* l, uint32_t pos, uint8_t type) {
+    if (l->val_count == l->val_cap) {
+        l->val_cap = l->val_cap ? l->val_cap * 2 : 64;
+        l->vals = load_recalloc(l, pos, l->vals, l->val_count, l->val_cap, 1,
+                                "operand stack");
+    }
+    l->vals[l->val_count++] = type;
+    if (l->val_count > l->val_max) {
+        l->val_max = l->val_count;
+    }
+}
+
+static uint8_t pop_val(Loader* l, uint32_t pos) {
+    Control* ctrl = &l->ctrls[l->ctrl_count - 1];
+    if (l->val_count == ctrl->height) {
+        if (ctrl->unreachable) {
+            return UNKNOWN;
+        }
+        load_fail(l, pos, "type mismatch");
+    }
+    return l->vals[--l->val_count];
+}
+
+static uint8_t pop_expect(Loader* l, uint32_t pos, uint8_t expected) {
+    uint8_t actual = pop_val(l, pos);
+    if (actual != expected && actual != UNKNOWN && expected != UNKNOWN) {
+        load_fail(l, pos, "type mismatch");
+    }
+    return actual == UNKNOWN ? expected : actual;
+}
+
+static void push_ctrl(Loader* l, uint32_t pos, uint8_t opcode, uint8_t result) {
+    Control* ctrl;
+    if (l->ctrl_count == l->ctrl_cap) {
+        l->ctrl_cap = l->ctrl_cap ? l->ctrl_cap * 2 : 16;
+        l->ctrls = load_recalloc(l, pos, l->ctrls, l->ctrl_count, l->ctrl_cap,
+                                 sizeof(Control), "control stack");
+    }
+    ctrl = &l->ctrls[l->ctrl_count++];
+    ctrl->opcode = opcode;
+    ctrl->result = result;
+    ctrl->height = l->val_count;
+    ctrl->unreachable = false;
+}
+
+static Control pop_ctrl(Loader* l, uint32_t pos) {
+    Control ctrl = l->ctrls[l->ctrl_count - 1];
+    if (ctrl.result != BLOCK) {
+        pop_expect(l, pos, ctrl.result);
+    }
+    if (l->val_count != ctrl.height) {
+        load_fail(l, pos, "type mismatch");
+    }
+    l->ctrl_count--;
+    return ctrl;
+}
+
+/* The type a branch to the label at depth carries, BLOCK for none */
+static uint8_t label_type(Loader* l, uint32_t pos, uint32_t depth) {
+    Control* ctrl;
+    if (depth >= l->ctrl_count) {
+        load_fail(l, pos, "unknown label");
+    }
+    ctrl = &l->ctrls[l->ctrl_count - 1 - depth];
+    return ctrl->opcode == 0x03 ? BLOCK : ctrl->result;
+}
+
+static void set_unreachable(Loader* l) {
+    Control* ctrl = &l->ctrls[l->ctrl_count - 1];
+    l->val_count = ctrl->height;
+    ctrl->unreachable = true;
+}
+
+static uint8_t read_block_type(Loader* l, uint32_t* pos) {
+    uint8_t type = load_byte(l, pos);
+    if (type != BLOCK && !is_value_type(type)) {
+        load_fail(l, *pos - 1, "invalid block type 0x%x", type);
+    }
+    return type;
+}
+
+/* The value type of local idx of function, 0 if there is none */
+static uint8_t local_type(Block* function, uint32_t idx) {
+    if (idx < function->type->param_count) {
+        return function->type->params[idx];
+    }
+    idx -= function->type->param_count;
+    return idx < function->local_count ? function->locals[idx] : 0;
+}
+
+static void pop_params(Loader* l, uint32_t pos, Type* type) {
+    uint32_t p;
+    for (p = type->param_count; p > 0; p--) {
+        pop_expect(l, pos, type->params[p - 1]);
+    }
+}
+
+static void push_results(Loader* l, uint32_t pos, Type* type) {
+    uint32_t r;
+    for (r = 0; r < type->result_count; r++) {
+        push_val(l, pos, type->results[r]);
+    }
+}
+
+/* The operands and result of the numeric instructions 0x45-0xc4, param2 is 0
+ * for unary ones */
+static void numeric_type(uint8_t opcode,
+                         uint8_t* param1,
+                         uint8_t* param2,
+                         uint8_t* result) {
+    /* Conversions, from 0xa7 */
+    static const uint8_t conversions[][2] = {
+        {I64, I32}, {F32, I32}, {F32, I32}, {F64, I32}, {F64, I32},
+        {I32, I64}, {I32, I64}, {F32, I64}, {F32, I64}, {F64, I64},
+        {F64, I64}, {I32, F32}, {I32, F32}, {I64, F32}, {I64, F32},
+        {F64, F32}, {I32, F64}, {I32, F64}, {I64, F64}, {I64, F64},
+        {F32, F64}, {F32, I32}, {F64, I64}, {I32, F32}, {I64, F64}};
+
+    *param2 = 0;
+    if (opcode == 0x45) { /* i32.eqz */
+        *param1 = I32;
+        *result = I32;
+    } else if (opcode <= 0x4f) { /* i32 comparisons */
+        *param1 = *param2 = I32;
+        *result = I32;
+    } else if (opcode == 0x50) { /* i64.eqz */
+        *param1 = I64;
+        *result = I32;
+    } else if (opcode <= 0x5a) { /* i64 comparisons */
+        *param1 = *param2 = I64;
+        *result = I32;
+    } else if (opcode <= 0x60) { /* f32 comparisons */
+        *param1 = *param2 = F32;
+        *result = I32;
+    } else if (opcode <= 0x66) { /* f64 comparisons */
+        *param1 = *param2 = F64;
+        *result = I32;
+    } else if (opcode <= 0x69) { /* i32 unary */
+        *param1 = *result = I32;
+    } else if (opcode <= 0x78) { /* i32 binary */
+        *param1 = *param2 = *result = I32;
+    } else if (opcode <= 0x7b) { /* i64 unary */
+        *param1 = *result = I64;
+    } else if (opcode <= 0x8a) { /* i64 binary */
+        *param1 = *param2 = *result = I64;
+    } else if (opcode <= 0x91) { /* f32 unary */
+        *param1 = *result = F32;
+    } else if (opcode <= 0x98) { /* f32 binary */
+        *param1 = *param2 = *result = F32;
+    } else if (opcode <= 0x9f) { /* f64 unary */
+        *param1 = *result = F64;
+    } else if (opcode <= 0xa6) { /* f64 binary */
+        *param1 = *param2 = *result = F64;
+    } else if (opcode <= 0xbf) {
+        *param1 = conversions[opcode - 0xa7][0];
+        *result = conversions[opcode - 0xa7][1];
+    } else if (opcode <= 0xc1) { /* i32 sign extensions */
+        *param1 = *result = I32;
+    } else { /* i64 sign extensions */
+        *param1 = *result = I64;
+    }
+}
+
+/* The value type and natural alignment (log2) of the loads 0x28-0x35 and
+ * stores 0x36-0x3e */
+static void memory_access_type(uint8_t opcode, uint8_t* type, uint32_t* align) {
+    static const uint8_t types[] = {I32, I64, F32, F64, I32, I32, I32, I32,
+                                    I64, I64, I64, I64, I64, I64, I32, I64,
+                                    F32, F64, I32, I32, I64, I64, I64};
+    static const uint8_t aligns[] = {2, 3, 2, 3, 0, 0, 1, 1, 0, 0, 1, 1,
+                                     2, 2, 2, 3, 2, 3, 0, 1, 0, 1, 2};
+    *type = types[opcode - 0x28];
+    *align = aligns[opcode - 0x28];
+}
+
+static void validate_function(Loader* l, Block* function) {
+    Module* m = l->m;
+    uint32_t pos = function->start_addr, end = function->end_addr;
+    uint32_t start, idx, count, t, align;
+    uint8_t opcode, type, param1, param2, result, type1, type2;
+    Control ctrl;
+
+    l->end = end + 1;
+    l->val_count = 0;
+    l->val_max = 0;
+    l->ctrl_count = 0;
+    push_ctrl(l, pos, 0x00,
+              function->type->result_count ? function->type->results[0]
+                                           : BLOCK);
+
+    while (l->ctrl_count > 0) {
+        if (pos > end) {
+            load_fail(l, end, "END opcode expected");
+        }
+        start = pos;
+        opcode = m->bytes[pos++];
+        switch (opcode) {
+            case 0x00: /* unreachable */
+                set_unreachable(l);
+                break;
+            case 0x01: /* nop */
+                break;
+            case 0x02: /* block */
+            case 0x03: /* loop */
+                push_ctrl(l, start, opcode, read_block_type(l, &pos));
+                break;
+            case 0x04: /* if */
+                type = read_block_type(l, &pos);
+                pop_expect(l, start, I32);
+                push_ctrl(l, start, opcode, type);
+                break;
+            case 0x05: /* else */
+                ctrl = pop_ctrl(l, start);
+                if (ctrl.opcode != 0x04) {
+                    load_fail(l, start, "else without if");
+                }
+                push_ctrl(l, start, 0x05, ctrl.result);
+                break;
+            case 0x0b: /* end */
+                ctrl = pop_ctrl(l, start);
+                /* An if without an else leaves nothing for its result */
+                if (ctrl.opcode == 0x04 && ctrl.result != BLOCK) {
+                    load_fail(l, start, "type mismatch");
+                }
+                if (ctrl.result != BLOCK) {
+                    push_val(l, start, ctrl.result);
+                }
+                break;
+            case 0x0c: /* br */
+                type = label_type(l, start, load_LEB(l, &pos, 32));
+                if (type != BLOCK) {
+                    pop_expect(l, start, type);
+                }
+                set_unreachable(l);
+                break;
+            case 0x0d: /* br_if */
+                type = label_type(l, start, load_LEB(l, &pos, 32));
+                pop_expect(l, start, I32);
+                if (type != BLOCK) {
+                    pop_expect(l, start, type);
+                    push_val(l, start, type);
+                }
+                break;
+            case 0x0e: /* br_table */
+                count = load_LEB(l, &pos, 32);
+                if (count > BR_TABLE_SIZE) {
+                    load_fail(l, start, "br_table size %u exceeds max %u",
+                              count, (uint32_t)BR_TABLE_SIZE);
+                }
+                /* The default label is last, every label must match it */
+                idx = pos;
+                for (t = 0; t < count; t++) {
+                    load_LEB(l, &pos, 32);
+                }
+                type = label_type(l, start, load_LEB(l, &pos, 32));
+                for (t = 0; t < count; t++) {
+                    if (label_type(l, start, load_LEB(l, &idx, 32)) != type) {
+                        load_fail(l, start, "type mismatch");
+                    }
+                }
+                pop_expect(l, start, I32);
+                if (type != BLOCK) {
+                    pop_expect(l, start, type);
+                }
+                set_unreachable(l);
+                break;
+            case 0x0f: /* return */
+                type = l->ctrls[0].result;
+                if (type != BLOCK) {
+                    pop_expect(l, start, type);
+                }
+                set_unreachable(l);
+                break;
+            case 0x10: /* call */
+                idx = load_LEB(l, &pos, 32);
+                if (idx >= m->function_count) {
+                    load_fail(l, start, "unknown function %u", idx);
+                }
+                pop_params(l, start, m->functions[idx].type);
+                push_results(l, start, m->functions[idx].type);
+                break;
+            case 0x11: /* call_indirect */
+                idx = load_LEB(l, &pos, 32);
+                if (load_byte(l, &pos) != 0x00) {
+                    load_fail(l, pos - 1, "zero flag expected");
+                }
+                if (l->table_count == 0) {
+                    load_fail(l, start, "unknown table");
+                }
+                if (idx >= m->type_count) {
+                    load_fail(l, start, "unknown type %u", idx);
+                }
+                pop_expect(l, start, I32);
+                pop_params(l, start, &m->types[idx]);
+                push_results(l, start, &m->types[idx]);
+                break;
+            case 0x1a: /* drop */
+                pop_val(l, start);
+                break;
+            case 0x1b: /* select */
+                pop_expect(l, start, I32);
+                type1 = pop_val(l, start);
+                type2 = pop_expect(l, start, type1);
+                push_val(l, start, type2);
+                break;
+            case 0x20: /* get_local */
+            case 0x21: /* set_local */
+            case 0x22: /* tee_local */
+                type = local_type(function, load_LEB(l, &pos, 32));
+                if (type == 0) {
+                    load_fail(l, start, "unknown local");
+                }
+                if (opcode != 0x20) {
+                    pop_expect(l, start, type);
+                }
+                if (opcode != 0x21) {
+                    push_val(l, start, type);
+                }
+                break;
+            case 0x23: /* get_global */
+            case 0x24: /* set_global */
+                idx = load_LEB(l, &pos, 32);
+                if (idx >= m->global_count) {
+                    load_fail(l, start, "unknown global %u", idx);
+                }
+                type = m->globals[idx].value_type;
+                if (opcode == 0x23) {
+                    push_val(l, start, type);
+                } else if (!m->global_mutable[idx]) {
+                    load_fail(l, start, "global is immutable");
+                } else {
+                    pop_expect(l, start, type);
+                }
+                break;
+            case 0x3f: /* current_memory */
+            case 0x40: /* grow_memory */
+                if (load_byte(l, &pos) != 0x00) {
+                    load_fail(l, pos - 1, "zero flag expected");
+                }
+                if (l->memory_count == 0) {
+                    load_fail(l, start, "unknown memory");
+                }
+                if (opcode == 0x40) {
+                    pop_expect(l, start, I32);
+                }
+                push_val(l, start, I32);
+                break;
+            case 0x41: /* i32.const */
+                load_LEB_signed(l, &pos, 32);
+                push_val(l, start, I32);
+                break;
+            case 0x42: /* i64.const */
+                load_LEB_signed(l, &pos, 64);
+                push_val(l, start, I64);
+                break;
+            case 0x43: /* f32.const */
+                load_need(l, pos, 4);
+                pos += 4;
+                push_val(l, start, F32);
+                break;
+            case 0x44: /* f64.const */
+                load_need(l, pos, 8);
+                pos += 8;
+                push_val(l, start, F64);
+                break;
+            default:
+                if (opcode >= 0x28 && opcode <= 0x3e) { /* loads and stores */
+                    memory_access_type(opcode, &type, &align);
+                    if (load_LEB(l, &pos, 32) > align) {
+                        load_fail(l, start,
+                                  "alignment must not be larger than natural");
+                    }
+                    load_LEB(l, &pos, 32); /* offset */
+                    if (l->memory_count == 0) {
+                        load_fail(l, start, "unknown memory");
                     }
-                    ASSERT(top >= 0, "blockstack underflow");
-                    block = blockstack[top--];
-                    block->end_addr = pos;
-                    if (block->block_type == 0x03) {
-                        /* loop: label after start */
-                        block->br_addr = block->start_addr + 2;
+                    if (opcode <= 0x35) {
+                        pop_expect(l, start, I32);
+                        push_val(l, start, type);
                     } else {
-                        /* block, if: label at end */
-                        block->br_addr = pos;
+                        pop_expect(l, start, type);
+                        pop_expect(l, start, I32);
                     }
-                    wa_debug(
-                        "      block start: 0x%x, end: 0x%x,"
-                        " br_addr: 0x%x, else_addr: 0x%x\n",
-                        block->start_addr, block->end_addr, block->br_addr,
-                        block->else_addr);
-                    break;
-            }
-            skip_immediates(m->bytes, &pos);
+                } else if (opcode >= 0x45 && opcode <= 0xc4) { /* numeric */
+                    numeric_type(opcode, &param1, &param2, &result);
+                    if (param2) {
+                        pop_expect(l, start, param2);
+                    }
+                    pop_expect(l, start, param1);
+                    push_val(l, start, result);
+                } else {
+                    load_fail(l, start, "illegal opcode 0x%x", opcode);
+                }
         }
+    }
 
-        ASSERT(top == -1, "Function ended in middle of block\n")
-        ASSERT(opcode == 0x0b, "Function block did not end with 0xb\n")
+    if (pos != end + 1) {
+        load_fail(l, pos, "operators remaining after the end of the function");
     }
+    function->max_height = l->val_max;
 }
 
 /* Stack machine (byte code related functions) */
 
-static void push_block(Module* m, Block* block, int sp) {
+/* The block, loop or if starting at pos, find_blocks made one for each */
+static Block* find_block(Module* m, uint32_t pos) {
+    uint32_t lo = 0, hi = m->block_count, mid;
+
+    while (hi - lo > 1) {
+        mid = lo + (hi - lo) / 2;
+        if (m->blocks[mid].start_addr <= pos) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    return &m->blocks[lo];
+}
+
+static void push_block(Instance* m, Block* block, int sp) {
     m->csp += 1;
     m->callstack[m->csp].block = block;
     m->callstack[m->csp].sp = sp;
@@ -815,31 +1553,17 @@ static void push_block(Module* m, Block* block, int sp) {
     m->callstack[m->csp].ra = m->pc;
 }
 
-static result_t pop_block(Module* m, Block** b) {
+static result_t pop_block(Instance* m, Block** b) {
     Frame* frame = &m->callstack[m->csp--];
     Type* t = frame->block->type;
 
-    /* TODO: validate return value if there is one */
-
+    /* The validator already checked the result type */
     m->fp = frame->fp; /* Restore frame pointer */
 
     if (t->result_count > 1) {
         return res_new_err("multi_return_not_supported");
     }
 
-    /* Validate the return value */
-    if (t->result_count == 1) {
-        if (m->stack[m->sp].value_type != t->results[0]) {
-            wa_warn("Typecheck fail");
-            /* sprintf(exception, "call type mismatch");
-              // return res_new_err("Bad ty");*/
-        }
-    } else if (t->result_count == 0) {
-    } else {
-        printf("BUG Q %d\n", t->result_count);
-        return res_new_err("bad res");
-    }
-
     /* Restore stack pointer */
     if (t->result_count == 1) {
         /* Save top value as result */
@@ -905,12 +1629,21 @@ static result_t pop_block(Module* m, Block** b) {
 /* Push params and locals on the stack and save a call frame on the call stack
  */
 /* Sets new pc value for the start of the function */
-void setup_call(Module* m, uint32_t fidx) {
+/* Fails without changing anything if the call stack is full or the stack
+ * can't hold the function's locals and every operand it pushes */
+result_t setup_call(Instance* m, uint32_t fidx) {
     uint32_t lidx;
     int p;
-    Block* func = &m->functions[fidx];
+    Block* func = &m->module->functions[fidx];
     Type* type = func->type;
 
+    if (m->csp + 1 >= CALLSTACK_SIZE ||
+        (uint64_t)(m->sp + 1) + 2 * (uint64_t)func->local_count +
+                func->max_height >
+            STACK_SIZE) {
+        return res_new_err("call stack exhausted");
+    }
+
     /* Push current frame on the call stack */
     push_block(m, func, m->sp - type->param_count);
 
@@ -926,7 +1659,6 @@ void setup_call(Module* m, uint32_t fidx) {
 
     /* Push locals (dropping extras) */
     m->fp = m->sp - type->param_count + 1;
-    /* TODO: validate arguments vs formal params */
 
     /* Push function locals */
     for (lidx = 0; lidx < func->local_count; lidx++) {
@@ -945,7 +1677,7 @@ void setup_call(Module* m, uint32_t fidx) {
 
     /* Set program counter to start of function */
     m->pc = func->start_addr;
-    return;
+    return res_new_ok();
 }
 
 static result_t conv_f64(StackValue* sv, StackValue* out, bool sign) {
@@ -976,10 +1708,44 @@ static result_t conv_f64(StackValue* sv, StackValue* out, bool sign) {
     return res_new_ok();
 }
 
-result_t interpret(Module* m) {
+/* Calls fidx of other, moving its arguments from the stack of m and its
+ * results back */
+static result_t call_other(Instance* m, Instance* other, uint32_t fidx) {
+    Type* type = other->module->functions[fidx].type;
+    uint32_t p, nesting;
+    result_t res;
+
+    /* Each call into another instance recurses in C, bound it apart from
+     * the call stacks */
+    if (m->nesting + 1 >= NESTING_SIZE ||
+        (uint64_t)(other->sp + 1) + type->param_count > STACK_SIZE) {
+        return res_new_err("call stack exhausted");
+    }
+    m->sp -= type->param_count;
+    for (p = 1; p <= type->param_count; p++) {
+        other->stack[++other->sp] = m->stack[m->sp + p];
+    }
+
+    nesting = other->nesting;
+    other->nesting = m->nesting + 1;
+    res = invoke(other, fidx);
+    other->nesting = nesting;
+    if (res_err(res)) {
+        return res;
+    }
+
+    other->sp -= type->result_count;
+    for (p = 1; p <= type->result_count; p++) {
+        m->stack[++m->sp] = other->stack[other->sp + p];
+    }
+    return res;
+}
+
+result_t interpret(Instance* m) {
     int ii;
-    uint32_t didx, prev_pages, delta;
-    uint8_t* bytes = m->bytes;
+    uint32_t didx;
+    Module* mod = m->module;
+    const uint8_t* bytes = mod->bytes;
     StackValue* stack = m->stack;
 
     uint32_t cur_pc;
@@ -995,9 +1761,13 @@ result_t interpret(Module* m) {
     double j, k, l;   /* F64 math */
     bool overflow = false;
     StackValue* sval;
+    Funcref* ref;
     result_t res;
+    /* The frames below the function setup_call pushed belong to whoever
+     * called into this instance, which can be this instance through another */
+    int top_csp = m->csp - 1;
 
-    while (m->pc < m->byte_count) {
+    while (m->pc < mod->byte_count) {
         opcode = bytes[m->pc];
         cur_pc = m->pc;
         m->pc += 1;
@@ -1014,27 +1784,27 @@ result_t interpret(Module* m) {
         switch (opcode) {
             /* Control flow operators */
             case 0x00: /* unreachable */
-                return res_new_err("Unreachable");
+                return res_new_err("unreachable");
             case 0x01: /* nop */
                 continue;
             case 0x02:                       /* block */
                 read_LEB(bytes, &m->pc, 32); /* ignore block type */
-                if (m->csp >= CALLSTACK_SIZE) {
+                if (m->csp + 1 >= CALLSTACK_SIZE) {
                     return res_new_err("call stack exhausted");
                 }
-                push_block(m, m->block_lookup[cur_pc], m->sp);
+                push_block(m, find_block(mod, cur_pc), m->sp);
                 continue;
             case 0x03:                       /* loop */
                 read_LEB(bytes, &m->pc, 32); /* ignore block type */
-                if (m->csp >= CALLSTACK_SIZE) {
+                if (m->csp + 1 >= CALLSTACK_SIZE) {
                     return res_new_err("call stack exhausted");
                 }
-                push_block(m, m->block_lookup[cur_pc], m->sp);
+                push_block(m, find_block(mod, cur_pc), m->sp);
                 continue;
             case 0x04:                       /* if */
                 read_LEB(bytes, &m->pc, 32); /* ignore block type */
-                block = m->block_lookup[cur_pc];
-                if (m->csp >= CALLSTACK_SIZE) {
+                block = find_block(mod, cur_pc);
+                if (m->csp + 1 >= CALLSTACK_SIZE) {
                     return res_new_err("call stack exhausted");
                 }
                 push_block(m, block, m->sp);
@@ -1074,7 +1844,7 @@ result_t interpret(Module* m) {
                      * "", block->type->result_count > 0 ?
                      * value_repr(&m->stack[m->sp]) : "_"); */
 
-                    if (m->csp == -1) {
+                    if (m->csp == top_csp) {
                         /* Return to top-level */
                         return res_new_ok();
                     } else {
@@ -1110,11 +1880,10 @@ result_t interpret(Module* m) {
                 count = read_LEB(bytes, &m->pc, 32);
                 if (count > BR_TABLE_SIZE) {
                     /* TODO: check this prior to runtime */
-                    result_t err;
-                    err.msg = calloc(1024, 1);
-                    sprintf(err.msg, "br_table size %d exceeds max %d\n", count,
+                    char* msg = calloc(1024, 1);
+                    sprintf(msg, "br_table size %d exceeds max %d\n", count,
                             BR_TABLE_SIZE);
-                    return err;
+                    return res_new_err_owned(msg);
                 }
                 for (ii = 0; ii < count; ii++) {
                     m->br_table[ii] = read_LEB(bytes, &m->pc, 32);
@@ -1152,24 +1921,26 @@ result_t interpret(Module* m) {
             case 0x10: /* call */
                 fidx = read_LEB(bytes, &m->pc, 32);
 
-                if (fidx < m->import_count) {
-                    thunk_out(m, fidx); /* import/thunk call */
+                if (fidx < mod->import_count) {
+                    res = thunk_out(m, fidx); /* import/thunk call */
+                    if (res_err(res)) {
+                        return res;
+                    }
                 } else {
-                    if (m->csp >= CALLSTACK_SIZE) {
-                        return res_new_err("call stack exhausted");
+                    res = setup_call(m, fidx); /* regular function call */
+                    if (res_err(res)) {
+                        return res;
                     }
-                    setup_call(m, fidx); /* regular function call */
 
                     wa_trace("      - calling function fidx: %d at: 0x%x\n",
                              fidx, m->pc);
                 }
                 continue;
             case 0x11:                              /* call_indirect */
-                tidx = read_LEB(bytes, &m->pc, 32); /* TODO: use tidx? */
-                (void)tidx;
+                tidx = read_LEB(bytes, &m->pc, 32);
                 read_LEB(bytes, &m->pc, 1); /* reserved immediate */
                 val = stack[m->sp--].value.uint32;
-                if (m->options.mangle_table_index) {
+                if (mod->options.mangle_table_index) {
                     /* val is the table address + the index (not sized for the
                      */
                     /* pointer size) so get the actual (sized) index */
@@ -1177,58 +1948,53 @@ result_t interpret(Module* m) {
                     wa_trace(
                         "      - entries: %p, original val: 0x%x, new val: "
                         "0x%x\n",
-                        m->table.entries, val, m->table.entries - val);
+                        m->table->entries, val, m->table->entries - val);
 
-                    /* val = val - (uint32_t)((uint64_t)m->table.entries &
+                    /* val = val - (uint32_t)((uint64_t)m->table->entries &
                      * 0xFFFFFFFF); */
-                    val = val - (uint32_t)(uint64_t)m->table.entries;
+                    val = val - (uint32_t)(uint64_t)m->table->entries;
                 }
-                if (val >= m->table.maximum) {
-                    result_t err;
-                    err.msg = calloc(1024, 1);
-                    sprintf(err.msg,
-                            "undefined element 0x%x (max: 0x%x) in table", val,
-                            m->table.maximum);
-                    return err;
+                if (val >= m->table->size) {
+                    char* msg = calloc(1024, 1);
+                    sprintf(msg, "undefined element 0x%x (size: 0x%x) in table",
+                            val, m->table->size);
+                    return res_new_err_owned(msg);
                 }
 
-                fidx = m->table.entries[val];
+                ref = &m->table->entries[val];
+                if (ref->instance == NULL) {
+                    char* msg = calloc(1024, 1);
+                    sprintf(msg, "uninitialized element %u", val);
+                    return res_new_err_owned(msg);
+                }
+                fidx = ref->fidx;
 
                 wa_trace(
                     "       - call_indirect tidx: %d, val: 0x%x, fidx: 0x%x\n",
                     tidx, val, fidx);
 
-                if (fidx < m->import_count) {
-                    thunk_out(m, fidx); /* import/thunk call */
-                } else {
-                    Block* func = &m->functions[fidx];
-                    Type* ftype = func->type;
+                if (!types_equal(ref->instance->module->functions[fidx].type,
+                                 &mod->types[tidx])) {
+                    return res_new_err(
+                        "indirect call type mismatch (call type and "
+                        "function type differ)");
+                }
 
-                    if (m->csp >= CALLSTACK_SIZE) {
-                        return res_new_err("call stack exhausted");
-                    }
-                    if (ftype->mask != m->types[tidx].mask) {
-                        return res_new_err(
-                            "indirect call type mismatch (call type and "
-                            "function type differ)");
+                if (ref->instance != m) {
+                    /* Put there by another instance sharing the table */
+                    res = call_other(m, ref->instance, fidx);
+                    if (res_err(res)) {
+                        return res;
                     }
-
-                    setup_call(m, fidx); /* regular function call */
-
-                    /* Validate signatures match */
-                    if (ftype->param_count + func->local_count !=
-                        m->sp - m->fp + 1) {
-                        return res_new_err(
-                            "indirect call type mismatch (param counts "
-                            "differ)");
+                } else if (fidx < mod->import_count) {
+                    res = thunk_out(m, fidx); /* import/thunk call */
+                    if (res_err(res)) {
+                        return res;
                     }
-                    for (f = 0; f < ftype->param_count; f++) {
-                        if (ftype->params[f] !=
-                            m->stack[m->fp + f].value_type) {
-                            return res_new_err(
-                                "indirect call type mismatch (param types "
-                                "differ)");
-                        }
+                } else {
+                    res = setup_call(m, fidx); /* regular function call */
+                    if (res_err(res)) {
+                        return res;
                     }
 
                     wa_trace(
@@ -1280,16 +2046,16 @@ result_t interpret(Module* m) {
                 arg = read_LEB(bytes, &m->pc, 32);
 
                 wa_trace("      - arg: 0x%x, got %s\n", arg,
-                         value_repr(&m->globals[arg]));
+                         value_repr(m->globals[arg]));
 
-                stack[++m->sp] = m->globals[arg];
+                stack[++m->sp] = *m->globals[arg];
                 continue;
             case 0x24: /* set_global */
                 arg = read_LEB(bytes, &m->pc, 32);
-                m->globals[arg] = stack[m->sp--];
+                *m->globals[arg] = stack[m->sp--];
 
                 wa_trace("      - arg: 0x%x, to %s\n", arg,
-                         value_repr(&m->globals[arg]));
+                         value_repr(m->globals[arg]));
 
                 continue;
 
@@ -1297,24 +2063,12 @@ result_t interpret(Module* m) {
             case 0x3f:                       /* current_memory */
                 read_LEB(bytes, &m->pc, 32); /* ignore reserved */
                 stack[++m->sp].value_type = I32;
-                stack[m->sp].value.uint32 = m->memory.pages;
+                stack[m->sp].value.uint32 = m->memory->pages;
                 continue;
             case 0x40:                       /* grow_memory */
                 read_LEB(bytes, &m->pc, 32); /* ignore reserved */
-                prev_pages = m->memory.pages;
-                delta = stack[m->sp].value.uint32;
-                stack[m->sp].value.uint32 = prev_pages;
-                if (delta == 0) {
-                    continue; /* No change */
-                } else if (delta + prev_pages > m->memory.maximum) {
-                    stack[m->sp].value.uint32 = -1;
-                    continue;
-                }
-                m->memory.pages += delta;
-                m->memory.bytes =
-                    arecalloc(m->memory.bytes, prev_pages * PAGE_SIZE,
-                              m->memory.pages * PAGE_SIZE, sizeof(uint32_t),
-                              "grow_memory: Module->memory.bytes");
+                stack[m->sp].value.uint32 =
+                    memory_grow(m->memory, stack[m->sp].value.uint32);
                 continue;
 
             /* Memory load operators */
@@ -1344,12 +2098,12 @@ result_t interpret(Module* m) {
                 if (offset + addr < addr) {
                     overflow = true;
                 }
-                maddr = m->memory.bytes + offset + addr;
-                if (maddr < m->memory.bytes) {
+                maddr = m->memory->bytes + offset + addr;
+                if (maddr < m->memory->bytes) {
                     overflow = true;
                 }
                 mem_end =
-                    m->memory.bytes + m->memory.pages * (uint32_t)PAGE_SIZE;
+                    m->memory->bytes + m->memory->pages * (uint32_t)PAGE_SIZE;
                 if (maddr + LOAD_SIZE[opcode - 0x28] > mem_end) {
                     overflow = true;
                 }
@@ -1357,10 +2111,10 @@ result_t interpret(Module* m) {
                     "      - addr: 0x%x, offset: 0x%x, maddr: %p, mem_end: "
                     "%p\n",
                     addr, offset, maddr, mem_end);
-                if (!m->options.disable_memory_bounds) {
+                if (!mod->options.disable_memory_bounds) {
                     if (overflow) {
                         wa_warn("memory start: %p, memory end: %p, maddr: %p\n",
-                                m->memory.bytes, mem_end, maddr);
+                                m->memory->bytes, mem_end, maddr);
                         return res_new_err("out of bounds memory access");
                     }
                 }
@@ -1457,12 +2211,12 @@ result_t interpret(Module* m) {
                 if (offset + addr < addr) {
                     overflow = true;
                 }
-                maddr = m->memory.bytes + offset + addr;
-                if (maddr < m->memory.bytes) {
+                maddr = m->memory->bytes + offset + addr;
+                if (maddr < m->memory->bytes) {
                     overflow = true;
                 }
                 mem_end =
-                    m->memory.bytes + m->memory.pages * (uint32_t)PAGE_SIZE;
+                    m->memory->bytes + m->memory->pages * (uint32_t)PAGE_SIZE;
                 if (maddr + LOAD_SIZE[opcode - 0x28] > mem_end) {
                     overflow = true;
                 }
@@ -1470,10 +2224,10 @@ result_t interpret(Module* m) {
                     "      - addr: 0x%x, offset: 0x%x, maddr: %p, mem_end: %p, "
                     "value: %s\n",
                     addr, offset, maddr, mem_end, value_repr(sval));
-                if (!m->options.disable_memory_bounds) {
+                if (!mod->options.disable_memory_bounds) {
                     if (overflow) {
                         wa_warn("memory start: %p, memory end: %p, maddr: %p\n",
-                                m->memory.bytes, mem_end, maddr);
+                                m->memory->bytes, mem_end, maddr);
                         return res_new_err("out of bounds memory access");
                     }
                 }
@@ -1749,48 +2503,48 @@ result_t interpret(Module* m) {
 
             /* unary f32 */
             case 0x8b:
-                stack[m->sp].value.f32 = fabs(stack[m->sp].value.f32);
+                stack[m->sp].value.uint32 &= 0x7fffffff;
                 break; /* f32.abs */
             case 0x8c:
-                stack[m->sp].value.f32 = -stack[m->sp].value.f32;
+                stack[m->sp].value.uint32 ^= 0x80000000;
                 break; /* f32.neg */
             case 0x8d:
-                stack[m->sp].value.f32 = ceil(stack[m->sp].value.f32);
+                stack[m->sp].value.f32 = ceil(quiet(stack[m->sp].value.f32));
                 break; /* f32.ceil */
             case 0x8e:
-                stack[m->sp].value.f32 = floor(stack[m->sp].value.f32);
+                stack[m->sp].value.f32 = floor(quiet(stack[m->sp].value.f32));
                 break; /* f32.floor */
             case 0x8f:
-                stack[m->sp].value.f32 = trunc(stack[m->sp].value.f32);
+                stack[m->sp].value.f32 = trunc(quiet(stack[m->sp].value.f32));
                 break; /* f32.trunc */
             case 0x90:
-                stack[m->sp].value.f32 = wa_rint(stack[m->sp].value.f32);
+                stack[m->sp].value.f32 = wa_rint(quiet(stack[m->sp].value.f32));
                 break; /* f32.nearest */
             case 0x91:
-                stack[m->sp].value.f32 = sqrt(stack[m->sp].value.f32);
+                stack[m->sp].value.f32 = sqrt(quiet(stack[m->sp].value.f32));
                 break; /* f32.sqrt */
 
             /* unary f64 */
             case 0x99:
-                stack[m->sp].value.f64 = fabs(stack[m->sp].value.f64);
+                stack[m->sp].value.uint64 &= 0x7fffffffffffffffULL;
                 break; /* f64.abs */
             case 0x9a:
-                stack[m->sp].value.f64 = -stack[m->sp].value.f64;
+                stack[m->sp].value.uint64 ^= 0x8000000000000000ULL;
                 break; /* f64.neg */
             case 0x9b:
-                stack[m->sp].value.f64 = ceil(stack[m->sp].value.f64);
+                stack[m->sp].value.f64 = ceil(quiet(stack[m->sp].value.f64));
                 break; /* f64.ceil */
             case 0x9c:
-                stack[m->sp].value.f64 = floor(stack[m->sp].value.f64);
+                stack[m->sp].value.f64 = floor(quiet(stack[m->sp].value.f64));
                 break; /* f64.floor */
             case 0x9d:
-                stack[m->sp].value.f64 = trunc(stack[m->sp].value.f64);
+                stack[m->sp].value.f64 = trunc(quiet(stack[m->sp].value.f64));
                 break; /* f64.trunc */
             case 0x9e:
-                stack[m->sp].value.f64 = wa_rint(stack[m->sp].value.f64);
+                stack[m->sp].value.f64 = wa_rint(quiet(stack[m->sp].value.f64));
                 break; /* f64.nearest */
             case 0x9f:
-                stack[m->sp].value.f64 = sqrt(stack[m->sp].value.f64);
+                stack[m->sp].value.f64 = sqrt(quiet(stack[m->sp].value.f64));
                 break; /* f64.sqrt */
 
             /* i32 binary */
@@ -1831,7 +2585,7 @@ result_t interpret(Module* m) {
                         break; /* i32.mul */
                     case 0x6d:
                         if (a == 0x80000000 && b == -1) {
-                            return res_new_err("integer divide by zero");
+                            return res_new_err("integer overflow");
                         }
                         c = (int32_t)a / (int32_t)b;
                         break; /* i32.div_s */
@@ -1857,14 +2611,15 @@ result_t interpret(Module* m) {
                     case 0x73:
                         c = a ^ b;
                         break; /* i32.xor */
+                    /* Shift counts are modulo the width, as in wasm */
                     case 0x74:
-                        c = a << b;
+                        c = a << (b & 31);
                         break; /* i32.shl */
                     case 0x75:
-                        c = (int32_t)a >> b;
+                        c = (int32_t)a >> (b & 31);
                         break; /* i32.shr_s */
                     case 0x76:
-                        c = a >> b;
+                        c = a >> (b & 31);
                         break; /* i32.shr_u */
                     case 0x77:
                         c = rotl32(a, b);
@@ -1914,7 +2669,7 @@ result_t interpret(Module* m) {
                         break; /* i64.mul */
                     case 0x7f:
                         if (d == 0x8000000000000000 && e == -1) {
-                            return res_new_err("integer divide by zero");
+                            return res_new_err("integer overflow");
                         }
                         f = (int64_t)d / (int64_t)e;
                         break; /* i64.div_s */
@@ -1941,13 +2696,13 @@ result_t interpret(Module* m) {
                         f = d ^ e;
                         break; /* i64.xor */
                     case 0x86:
-                        f = d << e;
+                        f = d << (e & 63);
                         break; /* i64.shl */
                     case 0x87:
-                        f = ((int64_t)d) >> e;
+                        f = ((int64_t)d) >> (e & 63);
                         break; /* i64.shr_s */
                     case 0x88:
-                        f = d >> e;
+                        f = d >> (e & 63);
                         break; /* i64.shr_u */
                     case 0x89:
                         f = rotl64(d, e);
@@ -1991,8 +2746,11 @@ result_t interpret(Module* m) {
                         i = wa_fmax(g, h);
                         break; /* f32.max */
                     case 0x98:
-                        i = _signbit(h) ? -fabs(g) : fabs(g);
-                        break; /* f32.copysign */
+                        /* On the bits, so NaN payloads are preserved */
+                        stack[m->sp].value.uint32 =
+                            (stack[m->sp].value.uint32 & 0x7fffffff) |
+                            (stack[m->sp + 1].value.uint32 & 0x80000000);
+                        continue; /* f32.copysign */
                 }
                 stack[m->sp].value_type = F32;
                 stack[m->sp].value.f32 = i;
@@ -2029,8 +2787,12 @@ result_t interpret(Module* m) {
                         l = wa_fmax(j, k);
                         break; /* f64.max */
                     case 0xa6:
-                        l = _signbit(k) ? -fabs(j) : fabs(j);
-                        break; /* f64.copysign */
+                        stack[m->sp].value.uint64 =
+                            (stack[m->sp].value.uint64 &
+                             0x7fffffffffffffffULL) |
+                            (stack[m->sp + 1].value.uint64 &
+                             0x8000000000000000ULL);
+                        continue; /* f64.copysign */
                 }
                 stack[m->sp].value.f64 = l;
                 continue;
@@ -2215,155 +2977,271 @@ result_t interpret(Module* m) {
                 break;
 
             default: {
-                result_t err;
-                err.msg = calloc(1024, 1);
-                sprintf(err.msg, "unrecognized opcode 0x%x\n", opcode);
-                return err;
+                char* msg = calloc(1024, 1);
+                sprintf(msg, "unrecognized opcode 0x%x\n", opcode);
+                return res_new_err_owned(msg);
             }
         }
     }
     return res_new_err("Unreachable"); /* We shouldn't reach here */
 }
 
-void run_init_expr(Module* m, uint8_t type, uint32_t* pc) {
-    /* Run the init_expr */
-    Block block = {0x01, 0, NULL, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0};
-    block.type = get_block_type(m, type);
-    block.start_addr = *pc;
-    m->pc = *pc;
-    push_block(m, &block, m->sp);
-    /* WARNING: running code here to get initial value! */
-    wa_info("  running init_expr at 0x%x: %s\n", m->pc, block_repr(&block));
-    interpret(m);
-    *pc = m->pc;
+/* Reads the init_expr at *pos, a single constant or get_global of an imported
+ * immutable global of the given type, and returns its value */
+static StackValue load_init_expr(Loader* l, uint8_t type, uint32_t* pos) {
+    Module* m = l->m;
+    uint32_t start = *pos, idx;
+    StackValue v;
 
-    ASSERT(m->stack[m->sp].value_type == type,
-           "init_expr type mismatch 0x%x != 0x%x", m->stack[m->sp].value_type,
-           type);
+    memset(&v, 0, sizeof(v));
+    switch (load_byte(l, pos)) {
+        case 0x41: /* i32.const */
+            v.value_type = I32;
+            v.value.uint32 = load_LEB_signed(l, pos, 32);
+            break;
+        case 0x42: /* i64.const */
+            v.value_type = I64;
+            v.value.int64 = load_LEB_signed(l, pos, 64);
+            break;
+        case 0x43: /* f32.const */
+            load_need(l, *pos, 4);
+            v.value_type = F32;
+            memcpy(&v.value.f32, m->bytes + *pos, 4);
+            *pos += 4;
+            break;
+        case 0x44: /* f64.const */
+            load_need(l, *pos, 8);
+            v.value_type = F64;
+            memcpy(&v.value.f64, m->bytes + *pos, 8);
+            *pos += 8;
+            break;
+        case 0x23: /* get_global */
+            idx = load_LEB(l, pos, 32);
+            if (idx >= m->import_global_count) {
+                load_fail(l, start, "unknown global %u", idx);
+            }
+            if (m->global_mutable[idx]) {
+                load_fail(l, start, "constant expression required");
+            }
+            v = m->globals[idx];
+            break;
+        case 0x0b: /* end */
+            load_fail(l, start, "type mismatch");
+            break;
+        default:
+            load_fail(l, start, "constant expression required");
+    }
+    /* A second constant leaves two values, the wrong type for one */
+    switch (load_byte(l, pos)) {
+        case 0x0b: /* end */
+            break;
+        case 0x23:
+        case 0x41:
+        case 0x42:
+        case 0x43:
+        case 0x44: /* get_global, constants */
+            load_fail(l, start, "type mismatch");
+            break;
+        default:
+            load_fail(l, *pos - 1, "constant expression required");
+    }
+    if (v.value_type != type) {
+        load_fail(l, start, "type mismatch");
+    }
+    wa_debug("  init_expr at 0x%x: %s\n", start, value_repr(&v));
+    return v;
 }
 
 /* Public API */
 
-uint32_t get_export_fidx(Module* m, char* name, uint32_t name_len) {
-    uint32_t f;
+uint32_t memory_grow(Memory* memory, uint32_t delta) {
+    uint32_t prev_pages = memory->pages;
+    uint8_t* bytes;
 
-    /* Find name function index */
-    for (f = 0; f < m->function_count; f++) {
-        char* fname = m->functions[f].export_name;
-        if (!fname) {
-            continue;
-        }
-        if (name_len != m->functions[f].name_len) {
-            continue;
-        }
+    if (delta == 0) {
+        return prev_pages; /* No change */
+    } else if (delta > memory->maximum - prev_pages) {
+        return -1;
+    }
+    /* Running out of memory fails the grow like reaching the maximum does */
+    bytes = realloc(memory->bytes, (size_t)(prev_pages + delta) * PAGE_SIZE);
+    if (bytes == NULL) {
+        return -1;
+    }
+    memset(bytes + (size_t)prev_pages * PAGE_SIZE, 0, (size_t)delta * PAGE_SIZE);
+    memory->bytes = bytes;
+    memory->pages += delta;
+    return prev_pages;
+}
 
-        if (memcmp(name, fname, name_len) == 0) {
-            return f;
+Export* get_export(Module* m, char* name, uint32_t name_len) {
+    uint32_t e;
+
+    for (e = 0; e < m->export_count; e++) {
+        Export* exp = &m->exports[e];
+        if (name_len == exp->name_len &&
+            memcmp(name, exp->export_name, name_len) == 0) {
+            return exp;
         }
     }
-    return -1;
+    return NULL;
 }
 
-Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
+uint32_t get_export_fidx(Module* m, char* name, uint32_t name_len) {
+    Export* exp = get_export(m, name, name_len);
+
+    if (!exp || exp->external_kind != KIND_FUNCTION) {
+        return -1;
+    }
+    return exp->index;
+}
+
+/* Reads the sections of the module into l->m, load_fail stops at the first
+ * error */
+static void load_sections(Loader* l,
+                          const uint8_t* bytes,
+                          uint32_t byte_count) {
     uint8_t vt;
-    uint32_t pos = 0, word;
-    Module* m;
+    uint32_t pos = 0, word, item_pos;
+    Module* m = l->m;
     uint32_t id;
     uint32_t slen;
-    uint32_t end_pos;
+    uint32_t end_pos, last_id = 0;
     char* name;
     StackValue* glob;
     uint32_t memorysize;
     uint32_t tablesize;
     uint32_t c, p, r, import_count, module_len, field_len, gidx, external_kind,
         type_index, fidx, f, table_count, tidx, memory_count, global_count, g,
-        e, n, s, b, l, export_count, element_count, offset, num_elem, seg_count,
-        size, body_count, body_size, payload_start, local_count, save_pos, lidx,
-        lecount;
-    int start_pos;
+        e, n, s, b, le, export_count, element_count, offset, num_elem,
+        seg_count, size, body_count, body_size, payload_start, local_count,
+        save_pos, lidx, lecount;
+    uint32_t start_pos;
     char *import_module, *import_field;
     uint8_t content_type, mutability, type1;
+    bool has_maximum = false;
 
     void* val = NULL;
+    Import import;
     /*char  *sym;*/
 
     Block* func;
     Memory* mval;
+    Funcref* entries;
+    uint8_t* copy;
     Block *functions, *function;
 
-    /* Allocate the module */
-#ifdef LOW_MEMORY_CONFIG
-    wa_warn("Using low memory configuration: sizeof(Module)=%ul.\n",
-            (unsigned int)sizeof(Module));
-    dumpMemoryInfo();
-#endif
-    m = acalloc(1, sizeof(Module), "Module");
-    m->options = options;
-
-    /* Empty stacks */
-    m->sp = -1;
-    m->fp = -1;
-    m->csp = -1;
-
-    m->bytes = calloc(1, byte_count);
+    if (m->options.execute_in_place) {
+        m->bytes = bytes;
+    } else {
+        copy = load_calloc(l, 0, 1, byte_count, "Module->bytes");
+        if (byte_count != 0) {
+            memcpy(copy, bytes, byte_count);
+        }
+        m->bytes = copy;
+    }
     m->byte_count = byte_count;
-    memcpy(m->bytes, bytes, byte_count);
-    m->block_lookup =
-        acalloc(m->byte_count, sizeof(Block*), "function->block_lookup");
-    m->start_function = -1;
 
-    /* Check the module */
+    /* Check the module, the magic as soon as there are 4 bytes of it */
+    if (byte_count < 4) {
+        load_fail(l, byte_count, "unexpected end");
+    }
     pos = 0;
     word = read_uint32(bytes, &pos);
-    ASSERT(word == WA_MAGIC, "Wrong module magic 0x%x\n", word);
+    if (word != WA_MAGIC) {
+        load_fail(l, 0, "magic header not detected");
+    }
+    if (byte_count < 8) {
+        load_fail(l, byte_count, "unexpected end");
+    }
     word = read_uint32(bytes, &pos);
-    ASSERT(word == WA_VERSION, "Wrong module version 0x%x\n", word);
+    if (word != WA_VERSION) {
+        load_fail(l, 4, "unknown binary version 0x%x", word);
+    }
 
     /* Read the sections */
+    l->end = byte_count;
     while (pos < byte_count) {
-        id = read_LEB(bytes, &pos, 7);
-        slen = read_LEB(bytes, &pos, 32);
+        item_pos = pos;
+        id = load_byte(l, &pos);
+        slen = load_LEB(l, &pos, 32);
+        /* As in the spec, only a length past the size of the whole module is
+         * out of bounds, a shorter one runs into the end of it */
+        if (slen > byte_count) {
+            load_fail(l, item_pos, "length out of bounds");
+        }
+        load_need(l, pos, slen);
         start_pos = pos;
         wa_debug("Reading section %d at 0x%x, length %d\n", id, pos, slen);
+        if (id != 0 && id <= last_id) {
+            load_fail(l, item_pos, "section out of order");
+        }
+        if (id != 0) {
+            last_id = id;
+        }
+        /* Nothing in the section may be read past its end */
+        l->end = start_pos + slen;
         switch (id) {
             case 0:
                 wa_warn("Parsing Custom(0) section (length: 0x%x)\n", slen);
                 end_pos = pos + slen;
-                name = read_string(bytes, &pos, NULL);
+                name = load_string(l, &pos, NULL);
                 wa_warn("  Section name '%s'\n", name);
                 if (strncmp(name, "dylink", 7) == 0) {
+                    free(name);
                     /* https://github.com/WebAssembly/tool-conventions/blob/master/DynamicLinking.md
                      */
                     /* TODO: make use of these */
-                    memorysize = read_LEB(bytes, &pos, 32);
-                    tablesize = read_LEB(bytes, &pos, 32);
+                    memorysize = load_LEB(l, &pos, 32);
+                    tablesize = load_LEB(l, &pos, 32);
                     (void)memorysize;
                     (void)tablesize;
                 } else {
-                    wa_error("Ignoring unknown custom section '%s'\n", name);
+                    wa_debug("Ignoring unknown custom section '%s'\n", name);
+                    free(name);
                 }
                 pos = end_pos;
                 break;
             case 1:
                 wa_warn("Parsing Type(1) section (length: 0x%x)\n", slen);
-                m->type_count = read_LEB(bytes, &pos, 32);
+                c = load_count(l, &pos);
                 m->types =
-                    acalloc(m->type_count, sizeof(Type), "Module->types");
+                    load_calloc(l, pos, c, sizeof(Type), "Module->types");
+                m->type_count = c;
 
                 for (c = 0; c < m->type_count; c++) {
                     Type* type = &m->types[c];
-                    type->form = read_LEB(bytes, &pos, 7);
-                    type->param_count = read_LEB(bytes, &pos, 32);
-                    type->params = acalloc(type->param_count, sizeof(uint32_t),
-                                           "type->params");
+                    item_pos = pos;
+                    type->form = load_LEB(l, &pos, 7);
+                    if (type->form != FUNC) {
+                        load_fail(l, item_pos, "malformed function type 0x%x",
+                                  type->form);
+                    }
+                    type->param_count = load_count(l, &pos);
+                    type->params =
+                        load_calloc(l, pos, type->param_count,
+                                    sizeof(uint32_t), "type->params");
                     for (p = 0; p < type->param_count; p++) {
-                        type->params[p] = read_LEB(bytes, &pos, 32);
+                        item_pos = pos;
+                        type->params[p] = load_LEB(l, &pos, 32);
+                        if (!is_value_type(type->params[p])) {
+                            load_fail(l, item_pos, "invalid value type");
+                        }
+                    }
+                    item_pos = pos;
+                    type->result_count = load_count(l, &pos);
+                    if (type->result_count > 1) {
+                        load_fail(l, item_pos, "invalid result arity");
                     }
-                    type->result_count = read_LEB(bytes, &pos, 32);
-                    type->results = acalloc(type->result_count,
-                                            sizeof(uint32_t), "type->results");
+                    type->results =
+                        load_calloc(l, pos, type->result_count,
+                                    sizeof(uint32_t), "type->results");
                     for (r = 0; r < type->result_count; r++) {
-                        type->results[r] = read_LEB(bytes, &pos, 32);
+                        item_pos = pos;
+                        type->results[r] = load_LEB(l, &pos, 32);
+                        if (!is_value_type(type->results[r])) {
+                            load_fail(l, item_pos, "invalid value type");
+                        }
                     }
                     /* TODO: calculate this above and remove get_type_mask */
                     type->mask = get_type_mask(type);
@@ -2373,12 +3251,15 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                 break;
             case 2:
                 wa_warn("Parsing Import(2) section (length: 0x%x)\n", slen);
-                import_count = read_LEB(bytes, &pos, 32);
+                import_count = load_count(l, &pos);
                 for (gidx = 0; gidx < import_count; gidx++) {
-                    import_module = read_string(bytes, &pos, &module_len);
-                    import_field = read_string(bytes, &pos, &field_len);
+                    item_pos = pos;
+                    l->import_module = load_string(l, &pos, &module_len);
+                    l->import_field = load_string(l, &pos, &field_len);
+                    import_module = l->import_module;
+                    import_field = l->import_field;
 
-                    external_kind = bytes[pos++];
+                    external_kind = load_byte(l, &pos);
 
                     wa_debug("  import: %d/%d, external_kind: %d, %s.%s\n",
                              gidx, import_count, external_kind, import_module,
@@ -2386,27 +3267,69 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
 
                     type_index = 0;
                     content_type = 0;
+                    mutability = 0;
 
                     switch (external_kind) {
                         case 0x00: /* Function */
-                            type_index = read_LEB(bytes, &pos, 32);
+                            type_index = load_LEB(l, &pos, 32);
+                            if (type_index >= m->type_count) {
+                                load_fail(l, item_pos, "unknown type %u",
+                                          type_index);
+                            }
                             break;
                         case 0x01: /* Table */
-                            parse_table_type(m, &pos);
+                            has_maximum = parse_table_type(l, &pos);
+                            if (l->table_count) {
+                                load_fail(l, item_pos, "multiple tables");
+                            }
+                            l->table_count = 1;
                             break;
                         case 0x02: /* Memory */
-                            parse_memory_type(m, &pos);
+                            has_maximum = parse_memory_type(l, &pos);
+                            if (l->memory_count) {
+                                load_fail(l, item_pos, "multiple memories");
+                            }
+                            l->memory_count = 1;
                             break;
                         case 0x03: /* Global */
-                            content_type = read_LEB(bytes, &pos, 7);
-                            /* TODO: use mutability */
-                            mutability = read_LEB(bytes, &pos, 1);
-                            (void)mutability;
+                            content_type = load_LEB(l, &pos, 7);
+                            if (!is_value_type(content_type)) {
+                                load_fail(l, item_pos, "invalid value type");
+                            }
+                            mutability = load_byte(l, &pos);
+                            if (mutability > 1) {
+                                load_fail(l, item_pos, "invalid mutability");
+                            }
                             break;
+                        default:
+                            load_fail(l, pos - 1, "malformed import kind %d",
+                                      external_kind);
                     }
 
                     wa_warn("Import mod=%s f=%s\n", import_module,
                             import_field);
+
+                    memset(&import, 0, sizeof(Import));
+                    import.kind = external_kind;
+                    if (m->options.resolve_import &&
+                        !m->options.resolve_import(m->options.resolve_env,
+                                                   import_module, import_field,
+                                                   external_kind, &import)) {
+                        load_unknown_import(l, item_pos, import_module,
+                                            import_field);
+                    }
+                    val = import.val;
+                    if (external_kind != 0x00 && !val) {
+                        load_unknown_import(l, item_pos, import_module,
+                                            import_field);
+                    }
+                    if (import.kind != external_kind ||
+                        (external_kind == 0x00 && import.type &&
+                         !types_equal(import.type, &m->types[type_index])) ||
+                        (external_kind == 0x03 &&
+                         import.mutability != mutability)) {
+                        load_fail(l, item_pos, "incompatible import type");
+                    }
                     /*sym = acalloc(module_len + field_len + 5, 1, "sym");*/
 
                     /*
@@ -2452,15 +3375,18 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                     switch (external_kind) {
                         case 0x00: /* Function */
                             fidx = m->function_count;
+                            m->functions = load_recalloc(
+                                l, pos, m->functions, fidx, fidx + 1,
+                                sizeof(Block), "Block(imports)");
                             m->import_count += 1;
                             m->function_count += 1;
-                            m->functions =
-                                arecalloc(m->functions, fidx, m->import_count,
-                                          sizeof(Block), "Block(imports)");
 
                             func = &m->functions[fidx];
+                            func->fidx = fidx;
                             func->import_module = import_module;
                             func->import_field = import_field;
+                            l->import_module = NULL;
+                            l->import_field = NULL;
                             func->type = &m->types[type_index];
                             wa_debug(
                                 "  import: %s.%s, fidx: 0x%x, type_index: "
@@ -2469,70 +3395,79 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                                 type_index);
 
                             func->func_ptr = (void* (*)(void))val;
+                            func->host_func = import.host_func;
+                            func->host_env = import.host_env;
                             break;
                         case 0x01: /* Table */ {
                             Table* tval = val;
                             if (!tval) {
-                                wa_warn("no tval\n");
-                                return NULL;
+                                m->table.size = m->table.initial;
+                                break;
                             }
-                            ASSERT(!m->table.entries,
-                                   "More than 1 table not supported\n");
-                            m->table.entries = val;
-                            ASSERT(m->table.initial <= tval->maximum,
-                                   "Imported table is not large enough\n");
-                            wa_warn("  setting table.entries to: %p\n",
-                                    *(uint32_t**)val);
-                            m->table.entries = *(uint32_t**)val;
+                            /* Its current size and maximum must be within the
+                             * limits of the import, a table declared without
+                             * a maximum has the largest one supported */
+                            if (m->table.initial > tval->size ||
+                                (has_maximum &&
+                                 tval->maximum > m->table.maximum)) {
+                                load_fail(l, item_pos,
+                                          "incompatible import type");
+                            }
+                            wa_warn("  importing table.entries: %p\n",
+                                    tval->entries);
+                            m->imported_table = tval;
                             m->table.size = tval->size;
                             m->table.maximum = tval->maximum;
-                            m->table.entries = tval->entries;
                             break;
                         }
                         case 0x02: /* Memory */
-                            ASSERT(!m->memory.bytes,
-                                   "More than 1 memory not supported\n");
                             mval = val;
                             if (!mval) {
-                                wa_warn("No mval");
-                                return NULL;
+                                m->memory.pages = m->memory.initial;
+                                break;
+                            }
+                            if (m->memory.initial > mval->pages ||
+                                (has_maximum &&
+                                 mval->maximum > m->memory.maximum)) {
+                                load_fail(l, item_pos,
+                                          "incompatible import type");
                             }
-                            ASSERT(m->memory.initial <= mval->maximum,
-                                   "Imported memory is not large enough\n");
                             wa_warn(
-                                "  setting memory pages: %d, max: %d, bytes: "
+                                "  importing memory pages: %d, max: %d, bytes: "
                                 "%p\n",
                                 mval->pages, mval->maximum, mval->bytes);
+                            m->imported_memory = mval;
                             m->memory.pages = mval->pages;
                             m->memory.maximum = mval->maximum;
-                            m->memory.bytes = mval->bytes;
                             break;
                         case 0x03: /* Global */
-                            if (!val) {
-                                wa_warn("No val");
-                                return NULL;
+                            glob = val;
+                            if (glob && glob->value_type != content_type) {
+                                load_fail(l, item_pos,
+                                          "incompatible import type");
                             }
+                            m->globals = load_recalloc(
+                                l, pos, m->globals, m->global_count,
+                                m->global_count + 1, sizeof(StackValue),
+                                "globals");
+                            m->imported_globals = load_recalloc(
+                                l, pos, m->imported_globals, m->global_count,
+                                m->global_count + 1, sizeof(StackValue*),
+                                "globals");
                             m->global_count += 1;
-                            m->globals = arecalloc(
-                                m->globals, m->global_count - 1,
-                                m->global_count, sizeof(StackValue), "globals");
+                            m->global_mutable = load_recalloc(
+                                l, pos, m->global_mutable, m->global_count - 1,
+                                m->global_count, sizeof(bool), "globals");
+                            m->global_mutable[m->global_count - 1] = mutability;
+                            m->import_global_count = m->global_count;
+                            /* Shared by the instances, the value at load time
+                             * is what init expressions see */
+                            m->imported_globals[m->global_count - 1] = glob;
+                            if (glob) {
+                                m->globals[m->global_count - 1] = *glob;
+                            }
                             glob = &m->globals[m->global_count - 1];
                             glob->value_type = content_type;
-
-                            switch (content_type) {
-                                case I32:
-                                    memcpy(&glob->value.uint32, val, 4);
-                                    break;
-                                case I64:
-                                    memcpy(&glob->value.uint64, val, 8);
-                                    break;
-                                case F32:
-                                    memcpy(&glob->value.f32, val, 4);
-                                    break;
-                                case F64:
-                                    memcpy(&glob->value.f64, val, 8);
-                                    break;
-                            }
                             wa_debug(
                                 "    setting global %d (content_type %d) to "
                                 "%p: %s\n",
@@ -2540,27 +3475,39 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                                 value_repr(glob));
                             break;
                         default:
-                            FATAL("Import of kind %d not supported\n",
-                                  external_kind);
+                            load_fail(l, item_pos, "malformed import kind %d",
+                                      external_kind);
                     }
+
+                    /* Only imported functions keep their names */
+                    free(l->import_module);
+                    free(l->import_field);
+                    l->import_module = NULL;
+                    l->import_field = NULL;
                 }
                 break;
             case 3:
                 wa_warn("Parsing Function(3) section (length: 0x%x)\n", slen);
-                m->function_count += read_LEB(bytes, &pos, 32);
+                f = m->import_count + load_count(l, &pos);
                 wa_debug("  import_count: %d, new count: %d\n", m->import_count,
-                         m->function_count);
+                         f);
 
-                functions = acalloc(m->function_count, sizeof(Block),
-                                    "Block(function)");
+                functions =
+                    load_calloc(l, pos, f, sizeof(Block), "Block(function)");
                 if (m->import_count != 0) {
                     memcpy(functions, m->functions,
                            sizeof(Block) * m->import_count);
                 }
+                free(m->functions);
                 m->functions = functions;
+                m->function_count = f;
 
                 for (f = m->import_count; f < m->function_count; f++) {
-                    tidx = read_LEB(bytes, &pos, 32);
+                    item_pos = pos;
+                    tidx = load_LEB(l, &pos, 32);
+                    if (tidx >= m->type_count) {
+                        load_fail(l, item_pos, "unknown type %u", tidx);
+                    }
                     m->functions[f].fidx = f;
                     m->functions[f].type = &m->types[tidx];
                     wa_debug("  function fidx: 0x%x, tidx: 0x%x\n", f, tidx);
@@ -2568,71 +3515,117 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                 break;
             case 4:
                 wa_warn("Parsing Table(4) section\n");
-                table_count = read_LEB(bytes, &pos, 32);
+                item_pos = pos;
+                table_count = load_count(l, &pos);
                 wa_debug("  table count: 0x%x\n", table_count);
-                ASSERT(table_count == 1, "More than 1 table not supported");
-
-                /* Allocate the table */
-                /* for (uint32_t c=0; c<table_count; c++) { */
-                parse_table_type(m, &pos);
-                /* If it's not imported then don't mangle it */
-                m->options.mangle_table_index = false;
-                m->table.entries = acalloc(m->table.size, sizeof(uint32_t),
-                                           "Module->table.entries");
-                /* } */
+                if (l->table_count + table_count > 1) {
+                    load_fail(l, item_pos, "multiple tables");
+                }
+
+                /* Every instance allocates its own entries */
+                for (c = 0; c < table_count; c++) {
+                    parse_table_type(l, &pos);
+                    l->table_count = 1;
+                    /* If it's not imported then don't mangle it */
+                    m->options.mangle_table_index = false;
+                }
                 break;
             case 5:
                 wa_warn("Parsing Memory(5) section\n");
-                memory_count = read_LEB(bytes, &pos, 32);
+                item_pos = pos;
+                memory_count = load_count(l, &pos);
                 wa_debug("  memory count: 0x%x\n", memory_count);
-                ASSERT(memory_count == 1, "More than 1 memory not supported\n");
+                if (l->memory_count + memory_count > 1) {
+                    load_fail(l, item_pos, "multiple memories");
+                }
 
-                /* Allocate memory */
-                /* for (uint32_t c=0; c<memory_count; c++) { */
-                parse_memory_type(m, &pos);
-                wa_debug(
-                    "parse memory section: about to allocate %i pages, total "
-                    "size %i Bytes ... \n",
-                    (int)m->memory.pages, (int)m->memory.pages * PAGE_SIZE);
-                m->memory.bytes = acalloc(1, m->memory.pages * PAGE_SIZE,
-                                          "parse memory section\n");
-                /* m->memory.bytes = acalloc(m->memory.pages*PAGE_SIZE, */
-                /* sizeof(uint32_t),  // GGr: shoudn't this be bytes (means 1)
-                 * ?! */
-                /* "parse memory section: Module->memory.bytes\n"); */
-                /* } */
+                /* Every instance allocates its own bytes */
+                for (c = 0; c < memory_count; c++) {
+                    parse_memory_type(l, &pos);
+                    l->memory_count = 1;
+                }
                 break;
             case 6:
                 wa_warn("Parsing Global(6) section\n");
-                global_count = read_LEB(bytes, &pos, 32);
+                global_count = load_count(l, &pos);
                 for (g = 0; g < global_count; g++) {
                     /* Same allocation Import of global above */
-                    type1 = read_LEB(bytes, &pos, 7);
-                    /* TODO: use mutability */
-                    mutability = read_LEB(bytes, &pos, 1);
-                    (void)mutability;
+                    item_pos = pos;
+                    type1 = load_LEB(l, &pos, 7);
+                    if (!is_value_type(type1)) {
+                        load_fail(l, item_pos, "invalid value type");
+                    }
+                    mutability = load_byte(l, &pos);
+                    if (mutability > 1) {
+                        load_fail(l, item_pos, "invalid mutability");
+                    }
                     gidx = m->global_count;
                     m->global_count += 1;
-                    m->globals = arecalloc(m->globals, gidx, m->global_count,
-                                           sizeof(StackValue), "globals");
-                    m->globals[gidx].value_type = type1;
-
-                    /* Run the init_expr to get global value */
-                    run_init_expr(m, type1, &pos);
-
-                    m->globals[gidx] = m->stack[m->sp--];
+                    m->globals =
+                        load_recalloc(l, pos, m->globals, gidx,
+                                      m->global_count, sizeof(StackValue),
+                                      "globals");
+                    m->global_mutable =
+                        load_recalloc(l, pos, m->global_mutable, gidx,
+                                      m->global_count, sizeof(bool), "globals");
+                    m->global_mutable[gidx] = mutability;
+                    m->globals[gidx] = load_init_expr(l, type1, &pos);
                 }
-                pos = start_pos + slen;
                 break;
             case 7:
                 wa_warn("Parsing Export(7) section (length: 0x%x)\n", slen);
-                export_count = read_LEB(bytes, &pos, 32);
+                export_count = load_count(l, &pos);
+                m->exports = load_calloc(l, pos, export_count, sizeof(Export),
+                                         "Module->exports");
+                m->export_count = export_count;
                 for (e = 0; e < export_count; e++) {
-                    uint32_t name_len = 0;
-                    char* name = read_string(bytes, &pos, &name_len);
-
-                    uint32_t kind = bytes[pos++];
-                    uint32_t index = read_LEB(bytes, &pos, 32);
+                    uint32_t name_len = 0, kind, index, limit;
+                    char* name;
+                    char* what;
+
+                    item_pos = pos;
+                    name = load_string(l, &pos, &name_len);
+                    /* Stored first so that the module frees it */
+                    m->exports[e].export_name = name;
+                    for (f = 0; f < e; f++) {
+                        if (m->exports[f].name_len == name_len + 1 &&
+                            memcmp(m->exports[f].export_name, name,
+                                   name_len) == 0) {
+                            load_fail(l, item_pos, "duplicate export name");
+                        }
+                    }
+                    kind = load_byte(l, &pos);
+                    index = load_LEB(l, &pos, 32);
+                    switch (kind) {
+                        case KIND_FUNCTION:
+                            limit = m->function_count;
+                            what = "function";
+                            break;
+                        case KIND_TABLE:
+                            limit = l->table_count;
+                            what = "table";
+                            break;
+                        case KIND_MEMORY:
+                            limit = l->memory_count;
+                            what = "memory";
+                            break;
+                        case KIND_GLOBAL:
+                            limit = m->global_count;
+                            what = "global";
+                            break;
+                        default:
+                            load_fail(l, item_pos, "malformed export kind %u",
+                                      kind);
+                            limit = 0;
+                            what = NULL;
+                    }
+                    if (index >= limit) {
+                        load_fail(l, item_pos, "unknown %s %u", what, index);
+                    }
+                    m->exports[e].export_name = name;
+                    m->exports[e].name_len = name_len + 1;
+                    m->exports[e].external_kind = kind;
+                    m->exports[e].index = index;
                     if (kind != 0x00) {
                         wa_warn(
                             "  ignoring non-function export '%s'"
@@ -2647,117 +3640,153 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                 break;
             case 8:
                 wa_warn("Parsing Start(8) section (length: 0x%x)\n", slen);
-                m->start_function = read_LEB(bytes, &pos, 32);
+                item_pos = pos;
+                m->start_function = load_LEB(l, &pos, 32);
+                if (m->start_function >= m->function_count) {
+                    load_fail(l, item_pos, "unknown function %u",
+                              m->start_function);
+                }
+                if (m->functions[m->start_function].type->param_count ||
+                    m->functions[m->start_function].type->result_count) {
+                    load_fail(l, item_pos, "start function");
+                }
                 break;
             case 9:
                 wa_warn("Parsing Element(9) section (length: 0x%x)\n", slen);
-                element_count = read_LEB(bytes, &pos, 32);
+                element_count = load_count(l, &pos);
+                m->elements = load_calloc(l, pos, element_count,
+                                          sizeof(Segment), "Module->elements");
+                m->element_count = element_count;
 
                 for (c = 0; c < element_count; c++) {
-                    uint32_t index = read_LEB(bytes, &pos, 32);
-                    ASSERT(index == 0, "Only 1 default table in MVP");
-
-                    /* Run the init_expr to get offset */
-                    run_init_expr(m, I32, &pos);
+                    uint32_t index;
+                    item_pos = pos;
+                    index = load_LEB(l, &pos, 32);
+                    if (index != 0 || l->table_count == 0) {
+                        load_fail(l, item_pos, "unknown table %u", index);
+                    }
 
-                    offset = m->stack[m->sp--].value.uint32;
+                    offset = load_init_expr(l, I32, &pos).value.uint32;
 
-                    if (m->options.mangle_table_index) {
+                    if (m->options.mangle_table_index && m->imported_table) {
                         /* offset is the table address + the index (not sized
                          * for the */
                         /* pointer size) so get the actual (sized) index */
+                        entries = m->imported_table->entries;
                         wa_debug(
                             "   origin offset: 0x%x, table addr: 0x%x, new "
                             "offset: 0x%x\n",
-                            offset, m->table.entries,
-                            offset - (uint32_t)(uint64_t)m->table.entries);
+                            offset, entries,
+                            offset - (uint32_t)(uint64_t)entries);
                         /* offset = offset -
-                         * (uint32_t)((uint64_t)m->table.entries & 0xFFFFFFFF);
+                         * (uint32_t)((uint64_t)entries & 0xFFFFFFFF);
                          */
-                        offset = offset - (uint32_t)(uint64_t)m->table.entries;
+                        offset = offset - (uint32_t)(uint64_t)entries;
                     }
 
-                    num_elem = read_LEB(bytes, &pos, 32);
-                    wa_warn("  table.entries: %p, offset: 0x%x\n",
-                            m->table.entries, offset);
+                    num_elem = load_count(l, &pos);
+                    wa_warn("  elements at offset: 0x%x\n", offset);
                     if (!m->options.disable_memory_bounds) {
-                        ASSERT(offset + num_elem <= m->table.size,
-                               "table overflow %d+%d > %d\n", offset, num_elem,
-                               m->table.size);
+                        if (offset + num_elem > m->table.size ||
+                            offset + num_elem < offset) {
+                            load_fail(l, item_pos,
+                                      "elements segment does not fit");
+                        }
                     }
+                    /* Copied into the table by instantiate */
+                    m->elements[c].offset = offset;
+                    m->elements[c].size = num_elem;
+                    m->elements[c].start = pos;
                     for (n = 0; n < num_elem; n++) {
-                        wa_debug(
-                            "  write table entries %p, offset: 0x%x, n: 0x%x, "
-                            "addr: %p\n",
-                            m->table.entries, offset, n,
-                            &m->table.entries[offset + n]);
-                        m->table.entries[offset + n] =
-                            read_LEB(bytes, &pos, 32);
+                        fidx = load_LEB(l, &pos, 32);
+                        if (fidx >= m->function_count) {
+                            load_fail(l, item_pos, "unknown function %u", fidx);
+                        }
                     }
                 }
-                pos = start_pos + slen;
                 break;
             /* 9 and 11 are similar so keep them together, 10 is below 11 */
             case 11:
                 wa_warn("Parsing Data(11) section (length: 0x%x)\n", slen);
-                seg_count = read_LEB(bytes, &pos, 32);
+                seg_count = load_count(l, &pos);
+                m->data = load_calloc(l, pos, seg_count, sizeof(Segment),
+                                      "Module->data");
+                m->data_count = seg_count;
                 for (s = 0; s < seg_count; s++) {
-                    uint32_t midx = read_LEB(bytes, &pos, 32);
-                    ASSERT(midx == 0, "Only 1 default memory in MVP");
-
-                    /* Run the init_expr to get the offset */
-                    run_init_expr(m, I32, &pos);
+                    uint32_t midx;
+                    item_pos = pos;
+                    midx = load_LEB(l, &pos, 32);
+                    if (midx != 0 || l->memory_count == 0) {
+                        load_fail(l, item_pos, "unknown memory %u", midx);
+                    }
 
-                    offset = m->stack[m->sp--].value.uint32;
+                    offset = load_init_expr(l, I32, &pos).value.uint32;
 
-                    /* Copy the data to the memory offset */
-                    size = read_LEB(bytes, &pos, 32);
+                    size = load_LEB(l, &pos, 32);
+                    load_need(l, pos, size);
                     if (!m->options.disable_memory_bounds) {
-                        ASSERT(offset + size <= m->memory.pages * PAGE_SIZE,
-                               "memory overflow %d+%d > %d\n", offset, size,
-                               (uint32_t)(m->memory.pages * PAGE_SIZE));
+                        if (offset + size > m->memory.pages * PAGE_SIZE ||
+                            offset + size < offset) {
+                            load_fail(l, item_pos,
+                                      "data segment does not fit");
+                        }
                     }
-                    wa_info(
-                        "  setting 0x%x bytes of memory at 0x%p + offset "
-                        "0x%x\n",
-                        size, m->memory.bytes, offset);
-                    memcpy(m->memory.bytes + offset, bytes + pos, size);
+                    /* Copied into the memory by instantiate */
+                    wa_info("  0x%x bytes of data at offset 0x%x\n", size,
+                            offset);
+                    m->data[s].offset = offset;
+                    m->data[s].size = size;
+                    m->data[s].start = pos;
                     pos += size;
                 }
 
                 break;
             case 10:
                 wa_warn("Parsing Code(10) section (length: 0x%x)\n", slen);
-                body_count = read_LEB(bytes, &pos, 32);
+                item_pos = pos;
+                body_count = load_count(l, &pos);
+                if (body_count != m->function_count - m->import_count) {
+                    load_fail(l, item_pos,
+                              "function and code section have inconsistent "
+                              "lengths");
+                }
                 for (b = 0; b < body_count; b++) {
                     function = &m->functions[m->import_count + b];
-                    body_size = read_LEB(bytes, &pos, 32);
+                    item_pos = pos;
+                    body_size = load_LEB(l, &pos, 32);
+                    load_need(l, pos, body_size);
                     payload_start = pos;
-                    local_count = read_LEB(bytes, &pos, 32);
+                    /* The locals must be in the body too */
+                    l->end = payload_start + body_size;
+                    local_count = load_count(l, &pos);
 
                     /* Local variable handling */
 
                     /* Get number of locals for alloc */
                     save_pos = pos;
                     function->local_count = 0;
-                    for (l = 0; l < local_count; l++) {
-                        lecount = read_LEB(bytes, &pos, 32);
-                        vt = read_LEB(bytes, &pos, 7);
-                        for (l = 0; l < lecount; l++) {
-                            wa_warn("local[%d]\n", lidx);
-                            function->local_count++;
+                    for (le = 0; le < local_count; le++) {
+                        item_pos = pos;
+                        lecount = load_LEB(l, &pos, 32);
+                        vt = load_LEB(l, &pos, 7);
+                        if (!is_value_type(vt)) {
+                            load_fail(l, item_pos, "invalid value type");
+                        }
+                        if (lecount > STACK_SIZE - function->local_count) {
+                            load_fail(l, item_pos, "too many locals");
                         }
+                        function->local_count += lecount;
                     }
                     function->locals =
-                        acalloc(function->local_count, sizeof(uint32_t),
-                                "function->locals");
+                        load_calloc(l, pos, function->local_count,
+                                    sizeof(uint32_t), "function->locals");
                     /* Restore position and read the locals */
                     pos = save_pos;
                     lidx = 0;
-                    for (l = 0; l < local_count; l++) {
-                        lecount = read_LEB(bytes, &pos, 32);
-                        vt = read_LEB(bytes, &pos, 7);
-                        for (l = 0; l < lecount; l++) {
+                    for (le = 0; le < local_count; le++) {
+                        lecount = load_LEB(l, &pos, 32);
+                        vt = load_LEB(l, &pos, 7);
+                        for (n = 0; n < lecount; n++) {
                             function->locals[lidx++] = vt;
                         }
                     }
@@ -2765,84 +3794,350 @@ Module* load_module(uint8_t* bytes, uint32_t byte_count, Options options) {
                     function->start_addr = pos;
                     function->end_addr = payload_start + body_size - 1;
                     function->br_addr = function->end_addr;
-                    ASSERT(bytes[function->end_addr] == 0x0b,
-                           "Code section did not end with 0x0b\n");
+                    /* Decoded as it's read, like the spec does */
+                    find_blocks(l, function);
                     pos = function->end_addr + 1;
+                    l->end = start_pos + slen;
                 }
                 break;
             default:
-                FATAL("Section %d unimplemented\n", id);
-                pos += slen;
+                load_fail(l, item_pos, "invalid section id");
+        }
+        if (pos != start_pos + slen) {
+            load_fail(l, item_pos, "section size mismatch");
+        }
+        l->end = byte_count;
+    }
+
+    /* Every function needs a body, then every body has to type check */
+    for (f = m->import_count; f < m->function_count; f++) {
+        if (m->functions[f].end_addr == 0) {
+            load_fail(l, byte_count,
+                      "function and code section have inconsistent lengths");
+        }
+        validate_function(l, &m->functions[f]);
+    }
+
+    if (l->unknown_import[0] != '\0') {
+        load_fail(l, l->unknown_import_pos, "%s", l->unknown_import);
+    }
+}
+
+/* Loads in a function of its own, so that no local variable of the function
+ * calling setjmp changes before load_fail jumps back */
+static bool load_guarded(Loader* l,
+                         const uint8_t* bytes,
+                         uint32_t byte_count) {
+    bool ok = false;
+
+    if (setjmp(l->fail) == 0) {
+        load_sections(l, bytes, byte_count);
+        ok = true;
+    }
+    /* The validation state isn't needed after loading either way */
+    free(l->vals);
+    free(l->ctrls);
+    free(l->import_module);
+    free(l->import_field);
+    return ok;
+}
+
+result_t load_module_checked(const uint8_t* bytes,
+                             uint32_t byte_count,
+                             Options options,
+                             Module** module,
+                             uint32_t* error_pos) {
+    Loader l;
+    Module* m;
+    char* msg;
+
+    *module = NULL;
+    if (error_pos) {
+        *error_pos = 0;
+    }
+
+    /* Allocate the module */
+    m = calloc(1, sizeof(Module));
+    if (m == NULL) {
+        return res_new_err("could not allocate the module");
+    }
+    m->options = options;
+    m->start_function = -1;
+
+    memset(&l, 0, sizeof(l));
+    l.m = m;
+    if (!load_guarded(&l, bytes, byte_count)) {
+        wa_warn("Load failed at byte 0x%x: %s\n", l.error_pos, l.error);
+        if (error_pos) {
+            *error_pos = l.error_pos;
+        }
+        /* Everything allocated so far is in the module */
+        module_destroy(m);
+        msg = malloc(strlen(l.error) + 1);
+        if (msg == NULL) {
+            return res_new_err("load failed");
+        }
+        strcpy(msg, l.error);
+        return res_new_err_owned(msg);
+    }
+
+    *module = m;
+    return res_new_ok();
+}
+
+Module* load_module(const uint8_t* bytes,
+                    uint32_t byte_count,
+                    Options options) {
+    Module* m;
+    uint32_t error_pos;
+    result_t result =
+        load_module_checked(bytes, byte_count, options, &m, &error_pos);
+
+    if (res_err(result)) {
+        wa_error("Could not load module at byte 0x%x: %s\n", error_pos,
+                 res_err_msg(result));
+        res_free(result);
+    }
+    return m;
+}
+
+void module_destroy(Module* m) {
+    uint32_t i;
+
+    if (m == NULL) {
+        return;
+    }
+
+    free(m->blocks);
+    if (!m->options.execute_in_place) {
+        free((void*)m->bytes);
+    }
+
+    if (m->types) {
+        for (i = 0; i < m->type_count; i++) {
+            free(m->types[i].params);
+            free(m->types[i].results);
+        }
+    }
+    free(m->types);
+
+    /* Export names are shared with functions, freed with the exports */
+    if (m->functions) {
+        for (i = 0; i < m->function_count; i++) {
+            free(m->functions[i].locals);
+            free(m->functions[i].import_module);
+            free(m->functions[i].import_field);
+        }
+    }
+    free(m->functions);
+
+    if (m->exports) {
+        for (i = 0; i < m->export_count; i++) {
+            free(m->exports[i].export_name);
+        }
+    }
+    free(m->exports);
+
+    free(m->globals);
+    free(m->imported_globals);
+    free(m->global_mutable);
+    free(m->elements);
+    free(m->data);
+    free(m);
+}
+
+/* Copies the element and data segments into the table and memory of i. The
+ * loader checked that they fit */
+static void init_segments(Instance* i) {
+    Module* m = i->module;
+    Segment* seg;
+    Funcref* ref;
+    uint32_t s, n, pos;
+
+    for (s = 0; s < m->element_count; s++) {
+        seg = &m->elements[s];
+        pos = seg->start;
+        for (n = 0; n < seg->size; n++) {
+            ref = &i->table->entries[seg->offset + n];
+            ref->instance = i;
+            ref->fidx = read_LEB(m->bytes, &pos, 32);
         }
     }
+    for (s = 0; s < m->data_count; s++) {
+        seg = &m->data[s];
+        memcpy(i->memory->bytes + seg->offset, m->bytes + seg->start,
+               seg->size);
+    }
+}
+
+result_t instantiate(Module* m, Instance** instance) {
+    Instance* i;
+    uint32_t fidx, g;
+    result_t result;
+
+    *instance = NULL;
+
+#ifdef LOW_MEMORY_CONFIG
+    wa_warn("Using low memory configuration: sizeof(Instance)=%u.\n",
+            (unsigned int)sizeof(Instance));
+#endif
+    i = calloc(1, sizeof(Instance));
+    if (i == NULL) {
+        return res_new_err("could not allocate the instance");
+    }
+    i->module = m;
 
-    find_blocks(m);
+    /* Empty stacks */
+    i->sp = -1;
+    i->fp = -1;
+    i->csp = -1;
+
+    /* An imported table or memory is shared, anything else starts out as the
+     * module describes it */
+    if (m->imported_table) {
+        i->table = m->imported_table;
+    } else {
+        i->table = &i->own_table;
+        i->own_table = m->table;
+        /* Every entry starts out uninitialized */
+        i->own_table.entries = calloc(m->table.size, sizeof(Funcref));
+    }
+    if (m->imported_memory) {
+        i->memory = m->imported_memory;
+    } else {
+        i->memory = &i->own_memory;
+        i->own_memory = m->memory;
+        i->own_memory.bytes = calloc(m->memory.pages, PAGE_SIZE);
+    }
+    i->own_globals = calloc(m->global_count, sizeof(StackValue));
+    i->globals = calloc(m->global_count, sizeof(StackValue*));
+    if ((m->table.size && !i->table->entries) ||
+        (m->memory.pages && !i->memory->bytes) ||
+        (m->global_count && (!i->own_globals || !i->globals))) {
+        instance_destroy(i);
+        return res_new_err("could not allocate the instance");
+    }
+    for (g = 0; g < m->global_count; g++) {
+        i->own_globals[g] = m->globals[g];
+        i->globals[g] = g < m->import_global_count ? m->imported_globals[g]
+                                                   : &i->own_globals[g];
+    }
+    init_segments(i);
 
     if (m->start_function != -1) {
-        uint32_t fidx = m->start_function;
-        result_t result;
+        fidx = m->start_function;
         wa_warn("Running start function 0x%x ('%s')\n", fidx,
                 m->functions[fidx].export_name);
 
         if (should_trace()) {
-            dump_stacks(m);
+            dump_stacks(i);
         }
 
         if (fidx < m->import_count) {
-            thunk_out(m, fidx); /* import/thunk call */
-        } else {
-            setup_call(m, fidx); /* regular function call */
-        }
-
-        if (m->csp < 0) {
-            /* start function was a direct external call */
-            result = res_new_ok();
+            result = thunk_out(i, fidx); /* import/thunk call */
         } else {
+            result = setup_call(i, fidx); /* regular function call */
             /* run the function setup by setup_call */
-            result = interpret(m);
+            if (!res_err(result)) {
+                result = interpret(i);
+            }
         }
         if (res_err(result)) {
-            FATAL("Exception: %s\n", res_err_msg(result));
+            /* Its segments are already in any imported table or memory,
+             * where its functions stay callable */
+            *instance = i;
+            return res_new_nest(result, "start function failed");
         }
     }
 
-    return m;
+    *instance = i;
+    return res_new_ok();
+}
+
+void instance_destroy(Instance* i) {
+    if (i == NULL) {
+        return;
+    }
+    free(i->globals);
+    free(i->own_globals);
+    free(i->own_table.entries);
+    free(i->own_memory.bytes);
+    free(i);
+}
+
+void* get_export_value(Instance* i, Export* exp) {
+    switch (exp->external_kind) {
+        case KIND_FUNCTION:
+            return &i->module->functions[exp->index];
+        case KIND_TABLE:
+            return i->table;
+        case KIND_MEMORY:
+            return i->memory;
+        case KIND_GLOBAL:
+            return i->globals[exp->index];
+    }
+    return NULL;
 }
 
 /* if entry == NULL,  attempt to invoke 'main' or '_main' */
 /* Return value of false means exception occured */
-result_t invoke(Module* mod, uint32_t fidx) {
+result_t invoke(Instance* i, uint32_t fidx) {
     result_t result;
+    int sp, fp, csp;
 
     if (should_trace()) {
-        dump_stacks(mod);
+        dump_stacks(i);
     }
 
-    setup_call(mod, fidx);
+    /* Stack state to return to if the call traps */
+    sp = i->sp - i->module->functions[fidx].type->param_count;
+    fp = i->fp;
+    csp = i->csp;
 
-    result = interpret(mod);
+    if (fidx < i->module->import_count) {
+        /* Exported import, call straight through to the host */
+        result = thunk_out(i, fidx);
+    } else {
+        result = setup_call(i, fidx);
+        if (!res_err(result)) {
+            result = interpret(i);
+        }
+    }
+
+    if (res_err(result)) {
+        i->sp = sp;
+        i->fp = fp;
+        i->csp = csp;
+    }
 
     if (should_trace()) {
-        dump_stacks(mod);
+        dump_stacks(i);
     }
 
     return result;
 }
 
-Module* snapshot(Module* m) {
-    Module* nm = calloc(1, sizeof(Module));
-    memcpy(nm, m, sizeof(Module));
-
-    nm->memory.bytes =
-        acalloc(1, nm->memory.pages * PAGE_SIZE, "parse memory section\n");
-    if (nm->memory.pages > 0) {
-        memcpy(nm->memory.bytes, m->memory.bytes, nm->memory.pages * PAGE_SIZE);
+Instance* snapshot(Instance* i) {
+    Instance* ni = calloc(1, sizeof(Instance));
+    memcpy(ni, i, sizeof(Instance));
+
+    /* The copy has its own memory, even if i's is imported */
+    ni->own_memory = *i->memory;
+    ni->own_memory.bytes =
+        acalloc(1, ni->own_memory.pages * PAGE_SIZE, "parse memory section\n");
+    if (ni->own_memory.pages > 0) {
+        memcpy(ni->own_memory.bytes, i->memory->bytes,
+               ni->own_memory.pages * PAGE_SIZE);
+    }
+    ni->memory = &ni->own_memory;
+    if (i->table == &i->own_table) {
+        ni->table = &ni->own_table;
     }
 
-    return nm;
+    return ni;
 }
 
-void snapshot_destroy(Module* m) {
-    free(m->memory.bytes);
-    free(m);
+void snapshot_destroy(Instance* i) {
+    free(i->own_memory.bytes);
+    free(i);
 }
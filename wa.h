@@ -13,26 +13,28 @@ extern "C" {
 
 #ifdef LOW_MEMORY_CONFIG
 #define PAGE_SIZE \
-    16 * 1024  // WARN: is it ok to change page size (other than 64kB) ?
+    16 * 1024  /* WARN: is it ok to change page size (other than 64kB) ? */
 #define STACK_SIZE 4 * 1024
 #define BLOCKSTACK_SIZE 4 * 1024
 #define CALLSTACK_SIZE 1024
 #define BR_TABLE_SIZE 4 * 1024
+#define NESTING_SIZE 0x20
 #else
-#define PAGE_SIZE 0x10000       // 65536
-#define STACK_SIZE 0x10000      // 65536
-#define BLOCKSTACK_SIZE 0x1000  // 4096
-#define CALLSTACK_SIZE 0x1000   // 4096
-#define BR_TABLE_SIZE 0x10000   // 65536
+#define PAGE_SIZE 0x10000       /* 65536 */
+#define STACK_SIZE 0x10000      /* 65536 */
+#define BLOCKSTACK_SIZE 0x1000  /* 4096 */
+#define CALLSTACK_SIZE 0x1000   /* 4096 */
+#define BR_TABLE_SIZE 0x10000   /* 65536 */
+#define NESTING_SIZE 0x100      /* 256 */
 #endif
 
-#define I32 0x7f      // -0x01
-#define I64 0x7e      // -0x02
-#define F32 0x7d      // -0x03
-#define F64 0x7c      // -0x04
-#define ANYFUNC 0x70  // -0x10
-#define FUNC 0x60     // -0x20
-#define BLOCK 0x40    // -0x40
+#define I32 0x7f      /* -0x01 */
+#define I64 0x7e      /* -0x02 */
+#define F32 0x7d      /* -0x03 */
+#define F64 0x7c      /* -0x04 */
+#define ANYFUNC 0x70  /* -0x10 */
+#define FUNC 0x60     /* -0x20 */
+#define BLOCK 0x40    /* -0x40 */
 
 #define KIND_FUNCTION 0
 #define KIND_TABLE 1
@@ -45,9 +47,17 @@ typedef struct Type {
     uint32_t* params;
     uint32_t result_count;
     uint32_t* results;
-    uint64_t mask;  // unique mask value for each type
+    uint64_t mask;  /* unique mask value for each type */
 } Type;
 
+struct Instance;
+
+/* Host callback for an imported function. The arguments are on top of
+ * instance->stack and are replaced by the results before returning. */
+typedef result_t (*HostFunction)(struct Instance* instance,
+                                 Type* type,
+                                 void* env);
+
 typedef union FuncPtr {
     void (*void_void)(void);
     void (*void_i32)(uint32_t);
@@ -58,23 +68,26 @@ typedef union FuncPtr {
     double (*f64_f64)(double);
 } FuncPtr;
 
-// A block or function
+/* A block or function */
 typedef struct Block {
-    uint8_t block_type;    // 0x00: function, 0x01: init_exp
-                           // 0x02: block, 0x03: loop, 0x04: if
-    uint32_t fidx;         // function only (index)
-    Type* type;            // params/results type
-    uint32_t local_count;  // function only
-    uint32_t* locals;      // function only
+    uint8_t block_type;    /* 0x00: function, 0x01: init_exp */
+                           /* 0x02: block, 0x03: loop, 0x04: if */
+    uint32_t fidx;         /* function only (index) */
+    Type* type;            /* params/results type */
+    uint32_t local_count;  /* function only */
+    uint32_t* locals;      /* function only */
+    uint32_t max_height;   /* function only, most operands it pushes */
     uint32_t start_addr;
     uint32_t end_addr;
-    uint32_t else_addr;       // if block only
-    uint32_t br_addr;         // blocks only
-    char* export_name;        // function only (exported)
-    size_t name_len;          // size of name buffer in bytes
-    char* import_module;      // function only (imported)
-    char* import_field;       // function only (imported)
-    void* (*func_ptr)(void);  // function only (imported)
+    uint32_t else_addr;       /* if block only */
+    uint32_t br_addr;         /* blocks only */
+    char* export_name;        /* function only (exported) */
+    size_t name_len;          /* size of name buffer in bytes */
+    char* import_module;      /* function only (imported) */
+    char* import_field;       /* function only (imported) */
+    void* (*func_ptr)(void);  /* function only (imported) */
+    HostFunction host_func;   /* function only (imported), replaces func_ptr */
+    void* host_env;           /* function only (imported), host_func env */
 } Block;
 
 typedef struct StackValue {
@@ -91,94 +104,209 @@ typedef struct StackValue {
 
 typedef struct Frame {
     Block* block;
-    // Saved state
+    /* Saved state */
     int sp;
     int fp;
     uint32_t ra;
 } Frame;
 
+/* A table entry, the function of the instance that put it in the table */
+typedef struct Funcref {
+    struct Instance* instance; /* NULL if the entry is uninitialized */
+    uint32_t fidx;
+} Funcref;
+
 typedef struct Table {
-    uint8_t elem_type;  // type of entries (only ANYFUNC in MVP)
-    uint32_t initial;   // initial table size
-    uint32_t maximum;   // maximum table size
-    uint32_t size;      // current table size
-    uint32_t* entries;
+    uint8_t elem_type;  /* type of entries (only ANYFUNC in MVP) */
+    uint32_t initial;   /* initial table size */
+    uint32_t maximum;   /* maximum table size */
+    uint32_t size;      /* current table size */
+    Funcref* entries;
 } Table;
 
 typedef struct Memory {
-    uint32_t initial;  // initial size (64K pages)
-    uint32_t maximum;  // maximum size (64K pages)
-    uint32_t pages;    // current size (64K pages)
-    uint8_t* bytes;    // memory area
+    uint32_t initial;  /* initial size (64K pages) */
+    uint32_t maximum;  /* maximum size (64K pages) */
+    uint32_t pages;    /* current size (64K pages) */
+    uint8_t* bytes;    /* memory area */
 } Memory;
 
+typedef struct Export {
+    char* export_name;       /* export name */
+    uint32_t name_len;       /* size of name buffer in bytes */
+    uint32_t external_kind;  /* KIND_* */
+    uint32_t index;          /* index into the functions/table/memory/globals */
+} Export;
+
+/* An element or data segment, copied into the table or memory of every
+ * instance */
+typedef struct Segment {
+    uint32_t offset;  /* first table entry or memory byte it is copied to */
+    uint32_t size;    /* number of function indexes or bytes */
+    uint32_t start;   /* position of its contents in the module bytes */
+} Segment;
+
+/* An import resolved by the host */
+typedef struct Import {
+    uint32_t kind;           /* KIND_* of the import, the resolver changes it
+                                when the name is of another kind */
+    void* val;               /* C function, Table*, Memory* or StackValue* */
+    HostFunction host_func;  /* function only, called instead of val when set */
+    void* host_env;          /* function only, passed to host_func */
+    Type* type;              /* function only, its type if the host knows it,
+                                checked against the type of the import */
+    bool mutability;         /* global only, whether the global is mutable */
+} Import;
+
+/* Resolves an import while loading, returns false if it is unknown. An
+ * imported table or memory is shared, not copied, so it must outlive the
+ * instances of the module */
+typedef bool (*ImportResolver)(void* env,
+                               char* module,
+                               char* field,
+                               uint32_t kind,
+                               Import* import);
+
 typedef struct Options {
-    // when true: host memory addresses will be outside allocated memory area
-    // so do not do bounds checking
+    /* when true: host memory addresses will be outside allocated memory area
+     * so do not do bounds checking */
     bool disable_memory_bounds;
 
-    // when true, table entries are accessed like this:
-    //   m->table.entries[m->table.entries-index]
-    // when false, table entires are accessed like this:
-    //   m->table.entries[index]
+    /* when true, table entries are accessed like this:
+     *   m->table->entries[m->table->entries-index]
+     * when false, table entires are accessed like this:
+     *   m->table->entries[index] */
     bool mangle_table_index;
 
     bool dlsym_trim_underscore;
+
+    /* when set, called for every import of the module. When NULL imported
+     * functions are left unresolved and trap when called */
+    ImportResolver resolve_import;
+    void* resolve_env;
+
+    /* when true the module reads its code and metadata straight from the
+     * bytes given to load_module instead of a copy. They are never written,
+     * so they can be in ROM or flash, and must outlive the module */
+    bool execute_in_place;
 } Options;
 
+/* A parsed and validated module, left unchanged by the instances created from
+ * it */
 typedef struct Module {
-    // Runtime state
-    uint32_t pc;                       // program counter
-    int sp;                            // operand stack pointer
-    int fp;                            // current frame pointer into stack
-    StackValue stack[STACK_SIZE];      // main operand stack
-    int csp;                           // callstack pointer
-    Frame callstack[CALLSTACK_SIZE];   // callstack
-    uint32_t br_table[BR_TABLE_SIZE];  // br_table branch indexes
+    char* path;       /* file path of the wasm module */
+    Options options;  /* Config options */
+
+    uint32_t byte_count;   /* number of bytes in the module */
+    const uint8_t* bytes;  /* module content/bytes */
 
-    char* path;       // file path of the wasm module
-    Options options;  // Config options
+    uint32_t type_count;  /* number of function types */
+    Type* types;          /* function types */
 
-    uint32_t byte_count;  // number of bytes in the module
-    uint8_t* bytes;       // module content/bytes
+    uint32_t import_count;    /* number of leading imports in functions */
+    uint32_t function_count;  /* number of function (including imports) */
+    Block* functions;         /* imported and locally defined functions */
+    uint32_t block_count;     /* number of blocks, loops and ifs */
+    Block* blocks;            /* blocks, loops and ifs of all functions, */
+                              /* sorted by start_addr */
+    uint32_t start_function;  /* function to run on instantiation */
 
-    uint32_t type_count;  // number of function types
-    Type* types;          // function types
+    Table table;    /* type and size of the table, entries is NULL */
+    Memory memory;  /* limits of the memory, bytes is NULL */
 
-    uint32_t import_count;    // number of leading imports in functions
-    uint32_t function_count;  // number of function (including imports)
-    Block* functions;         // imported and locally defined functions
-    Block** block_lookup;     // map of module byte position to Blocks
-                              // same length as byte_count
-    uint32_t start_function;  // function to run on module load
+    Table* imported_table;    /* the table given for the import, or NULL */
+    Memory* imported_memory;  /* the memory given for the import, or NULL */
 
-    Table table;
+    uint32_t global_count;          /* number of globals */
+    uint32_t import_global_count;   /* number of leading imports in globals */
+    StackValue* globals;            /* initial values of the globals */
+    StackValue** imported_globals;  /* the globals given for the imports */
+    bool* global_mutable;           /* whether each global is mutable */
 
-    Memory memory;
+    uint32_t export_count;  /* number of exports */
+    Export* exports;        /* exports of every kind */
 
-    uint32_t global_count;  // number of globals
-    StackValue* globals;    // globals
+    uint32_t element_count;  /* number of element segments */
+    Segment* elements;       /* element segments */
+
+    uint32_t data_count;  /* number of data segments */
+    Segment* data;        /* data segments */
 } Module;
 
-//
-// Function declarations (Public API)
-//
+/* A module with its own stacks, table, memory and globals */
+typedef struct Instance {
+    Module* module;  /* code and metadata, shared with other instances */
+
+    /* Runtime state */
+    uint32_t pc;                       /* program counter */
+    int sp;                            /* operand stack pointer */
+    int fp;                            /* current frame pointer into stack */
+    StackValue stack[STACK_SIZE];      /* main operand stack */
+    int csp;                           /* callstack pointer */
+    Frame callstack[CALLSTACK_SIZE];   /* callstack */
+    uint32_t br_table[BR_TABLE_SIZE];  /* br_table branch indexes */
+    uint32_t nesting;                  /* calls into other instances that
+                                          are running below this one */
+
+    Table* table;            /* own_table, or the exporter's if imported */
+    Memory* memory;          /* own_memory, or the exporter's if imported */
+    StackValue** globals;    /* own_globals, or the exporter's if imported */
+    Table own_table;         /* unused if the table is imported */
+    Memory own_memory;       /* unused if the memory is imported */
+    StackValue* own_globals; /* module->global_count globals, the imported
+                                ones unused */
+} Instance;
+
+
+/* Function declarations (Public API) */
+
 
 char* value_repr(StackValue* v);
+/* The thunk_in function calling fidx of the current instance with its
+ * arguments pushed, NULL if the stack can't hold the call */
 void (*setup_thunk_in(uint32_t fidx))(void);
-void setup_call(Module* m, uint32_t fidx);
-result_t interpret(Module* m);
+result_t setup_call(Instance* m, uint32_t fidx);
+result_t interpret(Instance* m);
 
 extern uint32_t get_export_fidx(Module* m, char* name, uint32_t name_sz);
-extern Module* load_module(uint8_t* bytes,
+extern Export* get_export(Module* m, char* name, uint32_t name_sz);
+/* The Block, Table, Memory or StackValue exp of instance->module refers to */
+extern void* get_export_value(Instance* instance, Export* exp);
+/* Grows memory by delta pages, returning the previous size in pages or
+ * (uint32_t)-1 if that would exceed its maximum or can't be allocated */
+extern uint32_t memory_grow(Memory* memory, uint32_t delta);
+/* Loads and validates a module. On failure *module is NULL and the error
+ * gives the reason, *error_pos (if not NULL) the byte offset in the module it
+ * was found at */
+extern result_t load_module_checked(const uint8_t* bytes,
+                                    uint32_t byte_count,
+                                    Options options,
+                                    Module** module,
+                                    uint32_t* error_pos);
+/* load_module_checked logging the error, NULL on failure */
+extern Module* load_module(const uint8_t* bytes,
                            uint32_t byte_count,
                            Options options);
-extern result_t invoke(Module* m, uint32_t fidx);
-extern Module* snapshot(Module* m);
-extern void snapshot_destroy(Module* m);
+/* Frees m and everything load_module allocated for it. The instances of m
+ * must be destroyed first. NULL is ignored */
+extern void module_destroy(Module* m);
+/* Creates an instance of m with the initial table, memory and globals of m
+ * and runs the start function. On failure *instance is NULL, except when the
+ * start function traps: the trap is nested in "start function failed" and
+ * *instance is still set and must be destroyed, since imported tables can
+ * refer to it */
+extern result_t instantiate(Module* m, Instance** instance);
+/* Frees instance and its table, memory and globals, except an imported table
+ * or memory. The entries it put in an imported table refer to it, so the
+ * instances calling through that table must be destroyed first. NULL is
+ * ignored */
+extern void instance_destroy(Instance* instance);
+extern result_t invoke(Instance* instance, uint32_t fidx);
+extern Instance* snapshot(Instance* instance);
+extern void snapshot_destroy(Instance* instance);
 
 #ifdef __cplusplus
 }
 #endif
 
-#endif  // of WAC_H
+#endif  /* of WAC_H */
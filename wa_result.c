@@ -6,6 +6,8 @@ result_t res_new_ok(void) {
     result_t x;
     x.status = S_OK;
     x.msg = 0;
+    x.parent = 0;
+    x.msg_owned = false;
     return x;
 }
 
@@ -13,6 +15,14 @@ result_t res_new_err(char* msg) {
     result_t x;
     x.status = S_ERR;
     x.msg = msg;
+    x.parent = 0;
+    x.msg_owned = false;
+    return x;
+}
+
+result_t res_new_err_owned(char* msg) {
+    result_t x = res_new_err(msg);
+    x.msg_owned = true;
     return x;
 }
 
@@ -21,9 +31,21 @@ result_t res_new_nest(result_t parent, char* msg) {
     x.status = S_ERR_NEST;
     x.msg = msg;
     x.parent = res_clone(&parent);
+    x.msg_owned = false;
     return x;
 }
 
+/* Releases the owned messages and the parents of an error */
+void res_free(result_t r) {
+    if (r.msg_owned) {
+        free(r.msg);
+    }
+    if (r.status == S_ERR_NEST && r.parent) {
+        res_free(*(result_t*)r.parent);
+        free(r.parent);
+    }
+}
+
 bool res_ok(result_t r) {
     return r.status == S_OK;
 }
@@ -8,11 +8,14 @@ typedef struct {
     status_t status;
     char* msg;
     void* parent;
+    bool msg_owned; /* msg was allocated and is released by res_free */
 } result_t;
 
 result_t res_new_ok(void);
 result_t res_new_err(char* msg);
+result_t res_new_err_owned(char* msg);
 result_t res_new_nest(result_t parent, char* msg);
+void res_free(result_t r);
 
 bool res_ok(result_t r);
 bool res_err(result_t r);
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The engine, main.c is the native CLI and not part of the library
+const SOURCES: &[&str] = &["platform.c", "thunk.c", "util.c", "wa.c", "wa_result.c"];
+
+/// The flags of the Makefile
+const FLAGS: &[&str] = &[
+    "-Wall", "-std=c89", "-g", "-O2", "-pedantic", "-fPIC", "-fno-omit-frame-pointer",
+    "-Wno-long-long", "-Wno-variadic-macros",
+];
+
+/// The structs of wa.h mirrored in src/ffi.rs, with their fields
+const LAYOUT: &[(&str, &[&str])] = &[
+    ("result_t", &["status", "msg", "parent", "msg_owned"]),
+    ("Type", &["form", "param_count", "params", "result_count", "results", "mask"]),
+    ("Block", &[
+        "block_type", "fidx", "type", "local_count", "locals", "max_height", "start_addr", "end_addr", "else_addr", "br_addr",
+        "export_name", "name_len", "import_module", "import_field", "func_ptr", "host_func", "host_env",
+    ]),
+    ("StackValue", &["value_type", "value"]),
+    ("Frame", &["block", "sp", "fp", "ra"]),
+    ("Funcref", &["instance", "fidx"]),
+    ("Table", &["elem_type", "initial", "maximum", "size", "entries"]),
+    ("Memory", &["initial", "maximum", "pages", "bytes"]),
+    ("Export", &["export_name", "name_len", "external_kind", "index"]),
+    ("Segment", &["offset", "size", "start"]),
+    ("Import", &["kind", "val", "host_func", "host_env", "type", "mutability"]),
+    ("Options", &[
+        "disable_memory_bounds", "mangle_table_index", "dlsym_trim_underscore", "resolve_import", "resolve_env",
+        "execute_in_place",
+    ]),
+    ("Module", &[
+        "path", "options", "byte_count", "bytes", "type_count", "types", "import_count", "function_count",
+        "functions", "block_count", "blocks", "start_function", "table", "memory", "imported_table",
+        "imported_memory", "global_count", "import_global_count", "globals", "imported_globals", "global_mutable",
+        "export_count", "exports", "element_count", "elements", "data_count", "data",
+    ]),
+    ("Instance", &[
+        "module", "pc", "sp", "fp", "stack", "csp", "callstack", "br_table", "nesting", "table", "memory", "globals",
+        "own_table", "own_memory", "own_globals",
+    ]),
+];
+
+/// The sizes in wa.h that depend on the configuration
+const CONSTANTS: &[&str] = &["PAGE_SIZE", "STACK_SIZE", "BLOCKSTACK_SIZE", "CALLSTACK_SIZE", "BR_TABLE_SIZE"];
+
+/// `(module, defines)` for every configuration of wa.h
+const CONFIGS: &[(&str, &[&str])] = &[("default", &[]), ("low_memory", &["-DLOW_MEMORY_CONFIG"])];
+
+enum Build {
+    Std,
+    Asan,
+    Ubsan,
+}
+
+/// Compiles the C sources in the repository root into a static library, with the sanitizer selected by
+/// the `asan`/`ubsan` features. The compiler is `$CC`, or `cc`
+fn main() {
+    let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("..");
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let ar = std::env::var("AR").unwrap_or_else(|_| "ar".to_string());
+
+    let build = match (std::env::var_os("CARGO_FEATURE_ASAN").is_some(), std::env::var_os("CARGO_FEATURE_UBSAN").is_some()) {
+        (false, false) => Build::Std,
+        (true, false) => Build::Asan,
+        (false, true) => Build::Ubsan,
+        (true, true) => panic!("the asan and ubsan features can't be enabled together"),
+    };
+    let clang = is_clang(&cc);
+
+    write_layout(&cc, &root, &out);
+
+    let mut flags = FLAGS.to_vec();
+    match build {
+        Build::Std => {}
+        Build::Asan => flags.push("-fsanitize=address"),
+        // Trapping needs no runtime library. Float division by zero is defined by IEEE 754 and wasm relies on it
+        Build::Ubsan if clang => flags.extend(["-fsanitize=undefined,local-bounds", "-fsanitize-trap=all"]),
+        Build::Ubsan => flags.extend(["-fsanitize=undefined", "-fsanitize-undefined-trap-on-error"]),
+    }
+
+    if std::env::var_os("CARGO_FEATURE_LOW_MEMORY").is_some() {
+        flags.push("-DLOW_MEMORY_CONFIG");
+    }
+
+    let mut objects = Vec::new();
+    for src in SOURCES {
+        let obj = out.join(Path::new(src).with_extension("o"));
+        run(Command::new(&cc).args(&flags).arg("-c").arg(root.join(src)).arg("-o").arg(&obj));
+        objects.push(obj);
+    }
+
+    let lib = out.join("libwasm89.a");
+    let _ = std::fs::remove_file(&lib);
+    run(Command::new(&ar).arg("crs").arg(&lib).args(&objects));
+
+    println!("cargo:rustc-link-search=native={}", out.display());
+    println!("cargo:rustc-link-lib=static=wasm89");
+    println!("cargo:rustc-link-lib=m");
+    if let Build::Asan = build {
+        link_asan_runtime(&cc, clang);
+    }
+
+    for src in SOURCES {
+        println!("cargo:rerun-if-changed={}", root.join(src).display());
+    }
+    for header in ["fixes.h", "platform.h", "thunk.h", "util.h", "wa.h", "wa_result.h"] {
+        println!("cargo:rerun-if-changed={}", root.join(header).display());
+    }
+    println!("cargo:rerun-if-env-changed=CC");
+    println!("cargo:rerun-if-env-changed=AR");
+}
+
+/// Writes `layout.rs` with the constants, sizes and field offsets of wa.h in every configuration, as
+/// reported by the C compiler. src/ffi.rs checks its mirrors against them at compile time
+fn write_layout(cc: &str, root: &Path, out: &Path) {
+    let mut c = String::from("#include <stdio.h>\n#include <stddef.h>\n#include \"wa.h\"\n\nint main(void) {\n");
+    for name in CONSTANTS {
+        c += &format!("    printf(\"pub const {name}: usize = %lu;\\n\", (unsigned long)({name}));\n");
+    }
+    for (ty, fields) in LAYOUT {
+        c += &format!("    printf(\"pub mod {ty} {{\\n\");\n");
+        c += &format!("    printf(\"    pub const SIZE: usize = %lu;\\n\", (unsigned long)sizeof({ty}));\n");
+        for field in *fields {
+            // `type` is a Rust keyword
+            let name = if *field == "type" { "r#type" } else { field };
+            c += &format!("    printf(\"    pub const {name}: usize = %lu;\\n\", (unsigned long)offsetof({ty}, {field}));\n");
+        }
+        c += "    printf(\"}\\n\");\n";
+    }
+    c += "    return 0;\n}\n";
+
+    let src = out.join("layout.c");
+    std::fs::write(&src, c).unwrap();
+
+    let mut rs = String::from("// Generated by build.rs from wa.h\n");
+    for (config, defines) in CONFIGS {
+        let exe = out.join(format!("layout_{config}"));
+        run(Command::new(cc).args(FLAGS).args(*defines).arg("-I").arg(root).arg(&src).arg("-o").arg(&exe));
+        let o = Command::new(&exe).output().unwrap_or_else(|e| panic!("failed to run {}: {e}", exe.display()));
+        assert!(o.status.success(), "{} failed with {}", exe.display(), o.status);
+
+        rs += &format!("\n#[allow(non_snake_case, non_upper_case_globals)]\npub mod {config} {{\n");
+        rs += &String::from_utf8(o.stdout).unwrap();
+        rs += "}\n";
+    }
+    std::fs::write(out.join("layout.rs"), rs).unwrap();
+}
+
+fn run(cmd: &mut Command) {
+    let status = cmd.status().unwrap_or_else(|e| panic!("failed to run {cmd:?}: {e}"));
+    assert!(status.success(), "{cmd:?} failed with {status}");
+}
+
+fn is_clang(cc: &str) -> bool {
+    Command::new(cc)
+        .arg("--version")
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).contains("clang"))
+        .unwrap_or(false)
+}
+
+/// rustc doesn't link with `-fsanitize=address`, so link the compiler's shared ASan runtime directly. It
+/// has to be found at run time too, clang's may need `LD_LIBRARY_PATH`
+fn link_asan_runtime(cc: &str, clang: bool) {
+    let candidates: &[&str] = if clang {
+        &["libclang_rt.asan.so", "libclang_rt.asan-x86_64.so", "libclang_rt.asan-aarch64.so"]
+    } else {
+        &["libasan.so"]
+    };
+
+    for name in candidates {
+        let Ok(o) = Command::new(cc).arg(format!("-print-file-name={name}")).output() else {
+            continue;
+        };
+        let path = PathBuf::from(String::from_utf8_lossy(&o.stdout).trim());
+        // Unknown files are printed back without a directory
+        if let (true, Some(dir)) = (path.is_absolute() && path.exists(), path.parent()) {
+            let lib = name.trim_start_matches("lib").trim_end_matches(".so");
+            println!("cargo:rustc-link-search=native={}", dir.display());
+            println!("cargo:rustc-link-lib=dylib={lib}");
+            return;
+        }
+    }
+    panic!("could not find the ASan runtime of {cc}");
+}
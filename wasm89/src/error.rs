@@ -0,0 +1,224 @@
+//! Errors of loading modules and calling their functions
+
+use std::ffi::CStr;
+use std::fmt;
+
+use crate::ffi;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// An error raised while loading a module or calling one of its functions
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The module couldn't be loaded
+    Load(LoadError),
+    /// The call can't be made, the export is missing or the arguments don't match its type
+    Call(String),
+    /// The wasm code trapped
+    Trap(Trap),
+    /// A host function failed
+    Host(BoxError),
+    /// An error with the context the engine added while unwinding
+    Nested { context: String, source: Box<Error> },
+}
+
+/// Why a host function failed, the call it failed returns it as [`Error::Host`]
+#[derive(Debug)]
+pub struct HostError(BoxError);
+
+/// Why a module couldn't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadError {
+    /// The byte offset in the module the error was found at, `None` if it isn't about the bytes
+    pub offset: Option<u32>,
+    pub message: String,
+}
+
+/// A trap of the wasm code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub message: String,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TrapKind {
+    Unreachable,
+    IntegerDivideByZero,
+    IntegerOverflow,
+    InvalidConversionToInteger,
+    MemoryOutOfBounds,
+    UndefinedElement,
+    UninitializedElement,
+    IndirectCallTypeMismatch,
+    CallStackExhausted,
+    /// Any other error of the engine
+    Other,
+}
+
+impl TrapKind {
+    /// The kind of a trap from the engine's message, these follow the spec's
+    fn from_message(message: &str) -> TrapKind {
+        match message {
+            "unreachable" => TrapKind::Unreachable,
+            "integer divide by zero" => TrapKind::IntegerDivideByZero,
+            "integer overflow" => TrapKind::IntegerOverflow,
+            "invalid conversion to integer" => TrapKind::InvalidConversionToInteger,
+            "out of bounds memory access" => TrapKind::MemoryOutOfBounds,
+            "call stack exhausted" => TrapKind::CallStackExhausted,
+            m if m.starts_with("undefined element") => TrapKind::UndefinedElement,
+            m if m.starts_with("uninitialized element") => TrapKind::UninitializedElement,
+            m if m.starts_with("indirect call type mismatch") => TrapKind::IndirectCallTypeMismatch,
+            _ => TrapKind::Other,
+        }
+    }
+}
+
+impl LoadError {
+    pub(crate) fn new(message: impl Into<String>) -> LoadError {
+        LoadError { offset: None, message: message.into() }
+    }
+}
+
+impl Trap {
+    pub(crate) fn new(kind: TrapKind, message: impl Into<String>) -> Trap {
+        Trap { kind, message: message.into() }
+    }
+}
+
+impl HostError {
+    /// A host function failure, for host functions to return
+    pub fn new(error: impl Into<BoxError>) -> HostError {
+        HostError(error.into())
+    }
+}
+
+impl Error {
+    pub(crate) fn host(error: impl Into<BoxError>) -> Error {
+        Error::Host(error.into())
+    }
+
+    /// The trap at the bottom of the causes, if any
+    pub fn trap(&self) -> Option<&Trap> {
+        match self {
+            Error::Trap(trap) => Some(trap),
+            Error::Nested { source, .. } => source.trap(),
+            _ => None,
+        }
+    }
+
+    /// Converts a failed `result_t` and releases it
+    pub(crate) fn from_result(r: ffi::ResultT) -> Error {
+        let e = Error::convert(&r);
+        unsafe { ffi::res_free(r) };
+        e
+    }
+
+    /// Converts the failed `result_t` of `load_module_checked` or `instantiate` and releases it. The
+    /// start function failing is nested in its context, anything else is a [`LoadError`] found at `offset`
+    pub(crate) fn from_load_result(r: ffi::ResultT, offset: Option<u32>) -> Error {
+        if r.status == ffi::Status::ErrNest {
+            return Error::from_result(r);
+        }
+        let Error::Trap(Trap { message, .. }) = Error::from_result(r) else {
+            unreachable!("a result_t without a parent converts to a trap")
+        };
+        Error::Load(LoadError { offset, message })
+    }
+
+    fn convert(r: &ffi::ResultT) -> Error {
+        let message = if r.msg.is_null() {
+            String::from("unknown error")
+        } else {
+            unsafe { CStr::from_ptr(r.msg) }.to_string_lossy().trim_end().to_string()
+        };
+
+        match r.status {
+            ffi::Status::ErrNest if !r.parent.is_null() => Error::Nested {
+                context: message,
+                source: Box::new(Error::convert(unsafe { &*(r.parent as *const ffi::ResultT) })),
+            },
+            _ => Error::Trap(Trap::new(TrapKind::from_message(&message), message)),
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Trap {}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte 0x{offset:x}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Load(e) => write!(f, "failed to load module: {e}"),
+            Error::Call(message) => f.write_str(message),
+            Error::Trap(trap) => write!(f, "{trap}"),
+            Error::Host(_) => f.write_str("host function failed"),
+            Error::Nested { context, .. } => f.write_str(context),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Host(e) => Some(e.as_ref()),
+            Error::Nested { source, .. } => Some(source.as_ref()),
+            Error::Load(_) | Error::Call(_) | Error::Trap(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for HostError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Lets a host function fail with `?` on the errors of the [`Memory`](crate::Memory) it's given
+impl From<Error> for HostError {
+    fn from(e: Error) -> HostError {
+        HostError::new(e)
+    }
+}
+
+impl From<Trap> for HostError {
+    fn from(trap: Trap) -> HostError {
+        HostError::new(trap)
+    }
+}
+
+impl From<HostError> for Error {
+    fn from(e: HostError) -> Error {
+        Error::Host(e.0)
+    }
+}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Error {
+        Error::Trap(trap)
+    }
+}
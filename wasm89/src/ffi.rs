@@ -0,0 +1,340 @@
+//! `repr(C)` mirrors of the declarations in `wa.h`
+//!
+//! The sizes and field offsets of every mirror are checked at compile time against the ones the C compiler
+//! reports, in every configuration of wa.h. [`Instance`] is the one of the configuration the engine is built
+//! in, selected by the `low-memory` feature.
+
+// Mirrors keep every field and variant of the C declarations, used or not
+#![allow(dead_code)]
+
+use std::mem::{offset_of, size_of};
+use std::os::raw::{c_char, c_int, c_void};
+
+/// Constants, sizes and offsets of wa.h by configuration, generated by build.rs
+pub mod layouts {
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+}
+
+/// The layout of the configuration the engine is built in
+#[cfg(not(feature = "low-memory"))]
+pub use layouts::default as layout;
+#[cfg(feature = "low-memory")]
+pub use layouts::low_memory as layout;
+
+pub use layout::{BR_TABLE_SIZE, CALLSTACK_SIZE, PAGE_SIZE, STACK_SIZE};
+
+/// Asserts at compile time that a mirror has the size and field offsets of the C struct in `layout`.
+/// Fields are named as in C unless given as `field: c_field`. Mirrors of a prefix of the struct end
+/// with `..` and skip the size check.
+#[macro_export]
+macro_rules! assert_layout {
+    ($layout:path, $ty:ty { $($field:ident $(: $c:ident)?),* $(,)? }) => {
+        const _: () = {
+            use $layout as layout;
+            assert!(::std::mem::size_of::<$ty>() == layout::SIZE);
+            $($crate::assert_layout!(@field $ty, $field $(: $c)?);)*
+        };
+    };
+    ($layout:path, $ty:ty { $($field:ident $(: $c:ident)?),* , .. }) => {
+        const _: () = {
+            use $layout as layout;
+            $($crate::assert_layout!(@field $ty, $field $(: $c)?);)*
+        };
+    };
+    (@field $ty:ty, $field:ident) => {
+        assert!(::std::mem::offset_of!($ty, $field) == layout::$field);
+    };
+    (@field $ty:ty, $field:ident : $c:ident) => {
+        assert!(::std::mem::offset_of!($ty, $field) == layout::$c);
+    };
+}
+
+pub const I32: u8 = 0x7f;
+pub const I64: u8 = 0x7e;
+pub const F32: u8 = 0x7d;
+pub const F64: u8 = 0x7c;
+
+pub const KIND_FUNCTION: u32 = 0;
+
+/// `bool` from `fixes.h`
+pub type Bool = c_int;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    Err = 1,
+    ErrNest = 2,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ResultT {
+    pub status: Status,
+    pub msg: *mut c_char,
+    pub parent: *mut c_void,
+    pub msg_owned: Bool,
+}
+
+#[repr(C)]
+pub struct Type {
+    pub form: u8,
+    pub param_count: u32,
+    pub params: *mut u32,
+    pub result_count: u32,
+    pub results: *mut u32,
+    pub mask: u64,
+}
+
+impl Type {
+    pub fn params(&self) -> &[u32] {
+        counted(self.params, self.param_count)
+    }
+
+    pub fn results(&self) -> &[u32] {
+        counted(self.results, self.result_count)
+    }
+}
+
+/// The loader leaves empty arrays NULL
+fn counted<'a>(ptr: *const u32, count: u32) -> &'a [u32] {
+    if count == 0 {
+        return &[];
+    }
+    unsafe { std::slice::from_raw_parts(ptr, count as usize) }
+}
+
+pub type HostFunction = Option<extern "C" fn(*mut Instance, *mut Type, *mut c_void) -> ResultT>;
+
+#[repr(C)]
+pub struct Block {
+    pub block_type: u8,
+    pub fidx: u32,
+    pub ty: *mut Type,
+    pub local_count: u32,
+    pub locals: *mut u32,
+    pub max_height: u32,
+    pub start_addr: u32,
+    pub end_addr: u32,
+    pub else_addr: u32,
+    pub br_addr: u32,
+    pub export_name: *mut c_char,
+    pub name_len: usize,
+    pub import_module: *mut c_char,
+    pub import_field: *mut c_char,
+    pub func_ptr: Option<extern "C" fn() -> *mut c_void>,
+    pub host_func: HostFunction,
+    pub host_env: *mut c_void,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union Value {
+    pub uint32: u32,
+    pub int32: i32,
+    pub uint64: u64,
+    pub int64: i64,
+    pub f32: f32,
+    pub f64: f64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct StackValue {
+    pub value_type: u8,
+    pub value: Value,
+}
+
+#[repr(C)]
+pub struct Frame {
+    pub block: *mut Block,
+    pub sp: c_int,
+    pub fp: c_int,
+    pub ra: u32,
+}
+
+#[repr(C)]
+pub struct Funcref {
+    pub instance: *mut Instance,
+    pub fidx: u32,
+}
+
+#[repr(C)]
+pub struct Table {
+    pub elem_type: u8,
+    pub initial: u32,
+    pub maximum: u32,
+    pub size: u32,
+    pub entries: *mut Funcref,
+}
+
+#[repr(C)]
+pub struct Memory {
+    pub initial: u32,
+    pub maximum: u32,
+    pub pages: u32,
+    pub bytes: *mut u8,
+}
+
+#[repr(C)]
+pub struct Export {
+    pub export_name: *mut c_char,
+    pub name_len: u32,
+    pub external_kind: u32,
+    pub index: u32,
+}
+
+#[repr(C)]
+pub struct Segment {
+    pub offset: u32,
+    pub size: u32,
+    pub start: u32,
+}
+
+#[repr(C)]
+pub struct Import {
+    pub kind: u32,
+    pub val: *mut c_void,
+    pub host_func: HostFunction,
+    pub host_env: *mut c_void,
+    pub ty: *mut Type,
+    pub mutability: Bool,
+}
+
+pub type ImportResolver =
+    Option<extern "C" fn(*mut c_void, *mut c_char, *mut c_char, u32, *mut Import) -> Bool>;
+
+#[repr(C)]
+pub struct Options {
+    pub disable_memory_bounds: Bool,
+    pub mangle_table_index: Bool,
+    pub dlsym_trim_underscore: Bool,
+    pub resolve_import: ImportResolver,
+    pub resolve_env: *mut c_void,
+    pub execute_in_place: Bool,
+}
+
+#[repr(C)]
+pub struct Module {
+    pub path: *mut c_char,
+    pub options: Options,
+
+    pub byte_count: u32,
+    pub bytes: *mut u8,
+
+    pub type_count: u32,
+    pub types: *mut Type,
+
+    pub import_count: u32,
+    pub function_count: u32,
+    pub functions: *mut Block,
+    pub block_count: u32,
+    pub blocks: *mut Block,
+    pub start_function: u32,
+
+    pub table: Table,
+    pub memory: Memory,
+
+    pub imported_table: *mut Table,
+    pub imported_memory: *mut Memory,
+
+    pub global_count: u32,
+    pub import_global_count: u32,
+    pub globals: *mut StackValue,
+    pub imported_globals: *mut *mut StackValue,
+    pub global_mutable: *mut Bool,
+
+    pub export_count: u32,
+    pub exports: *mut Export,
+
+    pub element_count: u32,
+    pub elements: *mut Segment,
+
+    pub data_count: u32,
+    pub data: *mut Segment,
+}
+
+/// `Instance`, with the sizes of its runtime stacks
+#[repr(C)]
+pub struct InstanceIn<const STACK: usize, const CALLSTACK: usize, const BR_TABLE: usize> {
+    pub module: *mut Module,
+
+    pub pc: u32,
+    pub sp: c_int,
+    pub fp: c_int,
+    pub stack: [StackValue; STACK],
+    pub csp: c_int,
+    pub callstack: [Frame; CALLSTACK],
+    pub br_table: [u32; BR_TABLE],
+    pub nesting: u32,
+
+    pub table: *mut Table,
+    pub memory: *mut Memory,
+    pub globals: *mut *mut StackValue,
+    pub own_table: Table,
+    pub own_memory: Memory,
+    pub own_globals: *mut StackValue,
+}
+
+pub type Instance = InstanceIn<STACK_SIZE, CALLSTACK_SIZE, BR_TABLE_SIZE>;
+
+macro_rules! assert_layouts {
+    ($($config:ident),*) => {$(
+        assert_layout!(layouts::$config::result_t, ResultT { status, msg, parent, msg_owned });
+        assert_layout!(layouts::$config::Type, Type { form, param_count, params, result_count, results, mask });
+        assert_layout!(layouts::$config::Block, Block {
+            block_type, fidx, ty: r#type, local_count, locals, max_height, start_addr, end_addr, else_addr, br_addr,
+            export_name, name_len, import_module, import_field, func_ptr, host_func, host_env,
+        });
+        assert_layout!(layouts::$config::StackValue, StackValue { value_type, value });
+        assert_layout!(layouts::$config::Frame, Frame { block, sp, fp, ra });
+        assert_layout!(layouts::$config::Funcref, Funcref { instance, fidx });
+        assert_layout!(layouts::$config::Table, Table { elem_type, initial, maximum, size, entries });
+        assert_layout!(layouts::$config::Memory, Memory { initial, maximum, pages, bytes });
+        assert_layout!(layouts::$config::Export, Export { export_name, name_len, external_kind, index });
+        assert_layout!(layouts::$config::Segment, Segment { offset, size, start });
+        assert_layout!(layouts::$config::Import, Import { kind, val, host_func, host_env, ty: r#type, mutability });
+        assert_layout!(layouts::$config::Options, Options {
+            disable_memory_bounds, mangle_table_index, dlsym_trim_underscore, resolve_import, resolve_env,
+            execute_in_place,
+        });
+        assert_layout!(layouts::$config::Module, Module {
+            path, options, byte_count, bytes, type_count, types, import_count, function_count, functions,
+            block_count, blocks, start_function, table, memory, imported_table, imported_memory, global_count,
+            import_global_count, globals, imported_globals, global_mutable, export_count, exports, element_count,
+            elements, data_count, data,
+        });
+        assert_layout!(
+            layouts::$config::Instance,
+            InstanceIn<{ layouts::$config::STACK_SIZE }, { layouts::$config::CALLSTACK_SIZE }, { layouts::$config::BR_TABLE_SIZE }> {
+                module, pc, sp, fp, stack, csp, callstack, br_table, nesting, table, memory, globals, own_table,
+                own_memory, own_globals,
+            }
+        );
+    )*};
+}
+
+assert_layouts!(default, low_memory);
+
+// The union is 8 bytes in every configuration
+const _: () = assert!(size_of::<Value>() == 8 && offset_of!(StackValue, value) == 8);
+
+extern "C" {
+    pub fn load_module(bytes: *const u8, byte_count: u32, options: Options) -> *mut Module;
+    pub fn load_module_checked(
+        bytes: *const u8,
+        byte_count: u32,
+        options: Options,
+        module: *mut *mut Module,
+        error_pos: *mut u32,
+    ) -> ResultT;
+    pub fn module_destroy(m: *mut Module);
+    pub fn instantiate(m: *mut Module, instance: *mut *mut Instance) -> ResultT;
+    pub fn instance_destroy(instance: *mut Instance);
+    pub fn get_export(m: *mut Module, name: *const c_char, name_len: u32) -> *mut Export;
+    pub fn get_export_value(instance: *mut Instance, exp: *mut Export) -> *mut c_void;
+    pub fn invoke(instance: *mut Instance, fidx: u32) -> ResultT;
+    pub fn memory_grow(memory: *mut Memory, delta: u32) -> u32;
+    pub fn res_free(r: ResultT);
+    pub fn wa_set_log_level(level: c_int);
+}
@@ -0,0 +1,150 @@
+//! Host functions written in Rust, provided to a module as its imports
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::os::raw::{c_char, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
+
+use crate::{ffi, Error, HostError, Memory, Value};
+
+type HostFn = dyn Fn(&mut Caller, &[Value]) -> Result<Vec<Value>, HostError>;
+
+thread_local! {
+    /// The error of the host function that failed the current call, the engine only sees a message
+    static HOST_ERROR: Cell<Option<Error>> = const { Cell::new(None) };
+}
+
+/// Takes the error of a host function that failed since the last call
+pub(crate) fn take_host_error() -> Option<Error> {
+    HOST_ERROR.with(Cell::take)
+}
+
+/// The instance calling a host function
+pub struct Caller<'a> {
+    instance: NonNull<ffi::Instance>,
+    _instance: PhantomData<&'a mut ffi::Instance>,
+}
+
+impl Caller<'_> {
+    /// The memory of the calling instance
+    pub fn memory(&mut self) -> Memory<'_> {
+        Memory::new(self.instance)
+    }
+}
+
+/// Host functions keyed by the module and field they are imported as
+#[derive(Default)]
+pub struct Imports {
+    funcs: HashMap<(String, String), Box<HostFn>>,
+}
+
+impl Imports {
+    /// No host functions, a module importing anything fails to load with these
+    pub fn new() -> Imports {
+        Imports::default()
+    }
+
+    /// Provides `func` as the function `module.field`, replacing any function already registered there.
+    /// The arguments have the types of the import and the results must match them too, otherwise the
+    /// call traps. A [`HostError`] returned by `func` stops the call, which returns it as [`Error::Host`].
+    pub fn func<F>(&mut self, module: &str, field: &str, func: F) -> &mut Imports
+    where
+        F: Fn(&mut Caller, &[Value]) -> Result<Vec<Value>, HostError> + 'static,
+    {
+        self.funcs.insert((module.to_string(), field.to_string()), Box::new(func));
+        self
+    }
+
+    /// Options resolving the imports of a module from `self`, which must not move while the module is loaded
+    pub(crate) fn options(&self) -> ffi::Options {
+        ffi::Options {
+            disable_memory_bounds: 0,
+            mangle_table_index: 0,
+            dlsym_trim_underscore: 0,
+            resolve_import: Some(resolve_import),
+            resolve_env: self as *const Imports as *mut c_void,
+            execute_in_place: 0,
+        }
+    }
+}
+
+/// Only functions can be imported, anything unknown fails the load
+extern "C" fn resolve_import(
+    env: *mut c_void,
+    module: *mut c_char,
+    field: *mut c_char,
+    kind: u32,
+    import: *mut ffi::Import,
+) -> ffi::Bool {
+    let imports = unsafe { &*(env as *const Imports) };
+    let (module, field) = unsafe { (CStr::from_ptr(module), CStr::from_ptr(field)) };
+    let (Ok(module), Ok(field)) = (module.to_str(), field.to_str()) else {
+        return 0;
+    };
+
+    if kind != ffi::KIND_FUNCTION {
+        return 0;
+    }
+    let Some(func) = imports.funcs.get(&(module.to_string(), field.to_string())) else {
+        return 0;
+    };
+
+    // The closure is boxed, so its address doesn't change while the module holds the imports
+    let import = unsafe { &mut *import };
+    import.host_func = Some(call_host);
+    import.host_env = func as *const Box<HostFn> as *mut c_void;
+    1
+}
+
+/// Pops the arguments from the stack, calls the host function and pushes its results
+extern "C" fn call_host(i: *mut ffi::Instance, ty: *mut ffi::Type, env: *mut c_void) -> ffi::ResultT {
+    let func = unsafe { &*(env as *const Box<HostFn>) };
+    let instance = unsafe { NonNull::new_unchecked(i) };
+    let (i, ty) = unsafe { (&mut *i, &*ty) };
+
+    let base = i.sp - ty.param_count as i32;
+    let args: Result<Vec<_>, _> = i.stack[(base + 1) as usize..(i.sp + 1) as usize].iter().map(Value::from_stack).collect();
+    i.sp = base;
+
+    let mut caller = Caller { instance, _instance: PhantomData };
+    // Unwinding into C is undefined, a panic becomes a trap
+    let results = args
+        .and_then(|args| {
+            catch_unwind(AssertUnwindSafe(|| func(&mut caller, &args).map_err(Error::from)))
+                .unwrap_or_else(|_| Err(Error::host("host function panicked")))
+        })
+        .and_then(|results| {
+            let expected = ty.results();
+            let matches = results.len() == expected.len()
+                && results.iter().zip(expected).all(|(r, &e)| u32::from(r.value_type()) == e);
+            if !matches {
+                return Err(Error::host(format!("host function returned {results:?}, expected types {expected:x?}")));
+            }
+            if (base + 1) as usize + results.len() > ffi::STACK_SIZE {
+                return Err(Error::host("stack overflow"));
+            }
+            Ok(results)
+        });
+
+    match results {
+        Ok(results) => {
+            for r in results {
+                i.sp += 1;
+                i.stack[i.sp as usize] = r.to_stack();
+            }
+            ffi::ResultT { status: ffi::Status::Ok, msg: ptr::null_mut(), parent: ptr::null_mut(), msg_owned: 0 }
+        }
+        Err(e) => {
+            HOST_ERROR.with(|h| h.set(Some(e)));
+            ffi::ResultT {
+                status: ffi::Status::Err,
+                msg: c"host function failed".as_ptr() as *mut c_char,
+                parent: ptr::null_mut(),
+                msg_owned: 0,
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Safe bindings to the wasm89 interpreter
+//!
+//! ```no_run
+//! use wasm89::{Instance, Module, Value};
+//!
+//! let module = Module::new(std::fs::read("add.wasm").unwrap()).unwrap();
+//! let mut instance = Instance::new(&module).unwrap();
+//! let sum = instance.call("add", &[Value::I32(1), Value::I32(2)]).unwrap();
+//! assert_eq!(sum, [Value::I32(3)]);
+//! ```
+
+use std::fmt;
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
+
+mod error;
+pub mod ffi;
+mod imports;
+mod memory;
+
+pub use error::{Error, HostError, LoadError, Trap, TrapKind};
+pub use imports::{Caller, Imports};
+pub use memory::Memory;
+
+/// Sets how much the interpreter logs, from 0 (nothing) to 5 (every instruction). The default of 4
+/// logs the loading of every module to stdout
+pub fn set_log_level(level: i32) {
+    unsafe { ffi::wa_set_log_level(level) }
+}
+
+/// A WebAssembly value
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    fn value_type(&self) -> u8 {
+        match self {
+            Value::I32(_) => ffi::I32,
+            Value::I64(_) => ffi::I64,
+            Value::F32(_) => ffi::F32,
+            Value::F64(_) => ffi::F64,
+        }
+    }
+
+    fn to_stack(self) -> ffi::StackValue {
+        let value = match self {
+            Value::I32(int32) => ffi::Value { int32 },
+            Value::I64(int64) => ffi::Value { int64 },
+            Value::F32(f32) => ffi::Value { f32 },
+            Value::F64(f64) => ffi::Value { f64 },
+        };
+        ffi::StackValue { value_type: self.value_type(), value }
+    }
+
+    fn from_stack(sv: &ffi::StackValue) -> Result<Value, Error> {
+        // The union member is selected by value_type, which the interpreter keeps in sync
+        unsafe {
+            Ok(match sv.value_type {
+                ffi::I32 => Value::I32(sv.value.int32),
+                ffi::I64 => Value::I64(sv.value.int64),
+                ffi::F32 => Value::F32(sv.value.f32),
+                ffi::F64 => Value::F64(sv.value.f64),
+                ty => return Err(Error::Call(format!("invalid value type 0x{ty:x} on the stack"))),
+            })
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Value {
+        Value::I32(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Value {
+        Value::I64(v)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Value {
+        Value::F32(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Value {
+        Value::F64(v)
+    }
+}
+
+/// A parsed and validated module, shared by the instances created from it. Cloning it is cheap
+///
+/// A module and its instances stay on the thread that loaded it. The host functions in its [`Imports`]
+/// needn't be `Send`, and the engine formats values for its log in global buffers:
+///
+/// ```compile_fail
+/// let module = wasm89::Module::new([0, 0x61, 0x73, 0x6d, 1, 0, 0, 0]).unwrap();
+/// std::thread::spawn(move || drop(module));
+/// ```
+#[derive(Clone)]
+pub struct Module {
+    loaded: Rc<Loaded>,
+}
+
+/// The module of the engine, freed with the last clone of [`Module`] and the last [`Instance`] of it
+struct Loaded {
+    module: NonNull<ffi::Module>,
+    // Called through pointers held by the module
+    _imports: Box<Imports>,
+}
+
+impl Module {
+    /// Loads `bytes`, a module that must not have any imports
+    pub fn new(bytes: impl AsRef<[u8]>) -> Result<Module, Error> {
+        Module::with_imports(bytes, Imports::new())
+    }
+
+    /// Loads `bytes` with its imports resolved from `imports`. Loading fails with an [`Error::Load`] if the
+    /// module is malformed or an import is missing
+    pub fn with_imports(bytes: impl AsRef<[u8]>, imports: Imports) -> Result<Module, Error> {
+        // load_module_checked copies the bytes, they don't need to outlive the module
+        Module::load(bytes.as_ref(), imports, false)
+    }
+
+    /// Loads `bytes` like [`Module::with_imports`], but the module reads its code and metadata from `bytes`
+    /// instead of a copy. They are never written, so they can be in ROM or flash
+    pub fn from_static(bytes: &'static [u8], imports: Imports) -> Result<Module, Error> {
+        Module::load(bytes, imports, true)
+    }
+
+    /// `bytes` must outlive the module if it is loaded in place
+    fn load(bytes: &[u8], imports: Imports, in_place: bool) -> Result<Module, Error> {
+        let byte_count = u32::try_from(bytes.len()).map_err(|_| Error::Load(LoadError::new("module too large")))?;
+        let imports = Box::new(imports);
+        let options = ffi::Options { execute_in_place: ffi::Bool::from(in_place), ..imports.options() };
+
+        let (mut m, mut offset) = (ptr::null_mut(), 0);
+        let r = unsafe { ffi::load_module_checked(bytes.as_ptr(), byte_count, options, &mut m, &mut offset) };
+        if r.status != ffi::Status::Ok {
+            return Err(Error::from_load_result(r, Some(offset)));
+        }
+
+        // Only NULL on failure
+        let module = NonNull::new(m).expect("load_module_checked returned no module");
+        Ok(Module { loaded: Rc::new(Loaded { module, _imports: imports }) })
+    }
+
+    /// The binary the module was loaded from, the engine's copy of it unless it was loaded in place
+    pub fn bytes(&self) -> &[u8] {
+        let m = unsafe { self.loaded.module.as_ref() };
+        if m.byte_count == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(m.bytes, m.byte_count as usize) }
+    }
+
+    fn raw(&self) -> *mut ffi::Module {
+        self.loaded.module.as_ptr()
+    }
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Module").field("len", &self.bytes().len()).finish_non_exhaustive()
+    }
+}
+
+impl Drop for Loaded {
+    fn drop(&mut self) {
+        unsafe { ffi::module_destroy(self.module.as_ptr()) };
+    }
+}
+
+/// An instance of a [`Module`] with its own stack, memory and globals, freed when it is dropped
+pub struct Instance {
+    instance: NonNull<ffi::Instance>,
+    // The code the instance runs
+    module: Module,
+}
+
+impl Instance {
+    /// Creates an instance of `module` and runs its start function. If that fails, its error is nested in
+    /// its context
+    pub fn new(module: &Module) -> Result<Instance, Error> {
+        let mut i = ptr::null_mut();
+        let r = unsafe { ffi::instantiate(module.raw(), &mut i) };
+        let host_error = imports::take_host_error();
+        if r.status != ffi::Status::Ok {
+            // Left after a failed start function, only for the tables it could be in, which Rust can't import
+            unsafe { ffi::instance_destroy(i) };
+            return Err(match (r.status, host_error) {
+                // The engine only saw the message of the host function that failed the start function
+                (ffi::Status::ErrNest, Some(host)) => match Error::from_result(r) {
+                    Error::Nested { context, .. } => Error::Nested { context, source: Box::new(host) },
+                    e => e,
+                },
+                _ => Error::from_load_result(r, None),
+            });
+        }
+
+        // Only NULL on failure
+        let instance = NonNull::new(i).expect("instantiate returned no instance");
+        Ok(Instance { instance, module: module.clone() })
+    }
+
+    /// The module the instance was created from
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// The memory of the instance, empty if it has none
+    pub fn memory(&mut self) -> Memory<'_> {
+        Memory::new(self.instance)
+    }
+
+    /// Calls the exported function `name`, returning its results
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Error> {
+        // Export names are stored with their NUL terminator
+        let c_name = [name.as_bytes(), &[0]].concat();
+        let name_len = u32::try_from(c_name.len()).map_err(|_| Error::Call("export name too long".to_string()))?;
+        let m = unsafe { &*self.module.raw() };
+        let i = unsafe { self.instance.as_mut() };
+
+        let export = unsafe { ffi::get_export(self.module.raw(), c_name.as_ptr().cast(), name_len).as_ref() };
+        let fidx = match export {
+            Some(e) if e.external_kind == ffi::KIND_FUNCTION => e.index,
+            Some(_) => return Err(Error::Call(format!("export {name} is not a function"))),
+            None => return Err(Error::Call(format!("unknown export {name}"))),
+        };
+
+        // fidx comes from the export section, which the loader checked against function_count
+        let ty = unsafe { &*(*m.functions.add(fidx as usize)).ty };
+        let params = ty.params();
+        if params.len() != args.len() {
+            return Err(Error::Call(format!("{name} expects {} arguments, got {}", params.len(), args.len())));
+        }
+        for (n, (&p, a)) in params.iter().zip(args).enumerate() {
+            if p != u32::from(a.value_type()) {
+                return Err(Error::Call(format!("argument {n} of {name} has the wrong type: {a:?}")));
+            }
+        }
+
+        let base = i.sp;
+        let needed = args.len().max(ty.result_count as usize);
+        if (base + 1) as usize + needed > ffi::STACK_SIZE {
+            return Err(Error::Call("stack overflow".to_string()));
+        }
+        for (n, a) in args.iter().enumerate() {
+            i.stack[(base + 1) as usize + n] = a.to_stack();
+        }
+        i.sp = base + args.len() as i32;
+
+        let r = unsafe { ffi::invoke(i, fidx) };
+        let host_error = imports::take_host_error();
+        if r.status != ffi::Status::Ok {
+            // invoke has already unwound the stack to base
+            return Err(host_error.unwrap_or_else(|| Error::from_result(r)));
+        }
+
+        let first = (base + 1) as usize;
+        let results = i.stack[first..first + ty.result_count as usize]
+            .iter()
+            .map(Value::from_stack)
+            .collect();
+        i.sp = base;
+        results
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        // The module has no imported table or memory, everything the instance points to is its own
+        unsafe { ffi::instance_destroy(self.instance.as_ptr()) };
+    }
+}
@@ -0,0 +1,117 @@
+//! Access to the linear memory of an instance
+
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+use crate::{ffi, Error, Trap, TrapKind};
+
+/// The linear memory of an instance, borrowed from it
+///
+/// Growing may move the bytes, so [`Memory::grow`] takes `&mut self` and no slice from
+/// [`Memory::data`] can outlive it:
+///
+/// ```compile_fail
+/// # fn f(instance: &mut wasm89::Instance) {
+/// let mut memory = instance.memory();
+/// let data = memory.data();
+/// memory.grow(1).unwrap();
+/// println!("{}", data[0]);
+/// # }
+/// ```
+pub struct Memory<'a> {
+    memory: NonNull<ffi::Memory>,
+    _instance: PhantomData<&'a mut ffi::Instance>,
+}
+
+macro_rules! accessors {
+    ($($read:ident, $write:ident, $ty:ty;)*) => {$(
+        #[doc = concat!("Reads a little-endian `", stringify!($ty), "` at `offset`")]
+        pub fn $read(&self, offset: u32) -> Result<$ty, Error> {
+            let mut bytes = [0; size_of::<$ty>()];
+            self.read(offset, &mut bytes)?;
+            Ok(<$ty>::from_le_bytes(bytes))
+        }
+
+        #[doc = concat!("Writes a little-endian `", stringify!($ty), "` at `offset`")]
+        pub fn $write(&mut self, offset: u32, value: $ty) -> Result<(), Error> {
+            self.write(offset, &value.to_le_bytes())
+        }
+    )*};
+}
+
+impl Memory<'_> {
+    /// The memory of `instance`, borrowed for as long as the caller borrows the instance
+    pub(crate) fn new(instance: NonNull<ffi::Instance>) -> Self {
+        let memory = unsafe { NonNull::new_unchecked((*instance.as_ptr()).memory) };
+        Memory { memory, _instance: PhantomData }
+    }
+
+    fn raw(&self) -> &ffi::Memory {
+        unsafe { self.memory.as_ref() }
+    }
+
+    /// The current size in pages of [`ffi::PAGE_SIZE`] bytes
+    pub fn size_pages(&self) -> u32 {
+        self.raw().pages
+    }
+
+    /// The bytes of the memory. Growing it may move them, so the slice can't be kept across a
+    /// [`Memory::grow`]
+    pub fn data(&self) -> &[u8] {
+        let m = self.raw();
+        if m.bytes.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(m.bytes, m.pages as usize * ffi::PAGE_SIZE) }
+    }
+
+    /// The bytes of the memory, writable. Like [`Memory::data`], the slice is invalidated by a grow
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let len = self.data().len();
+        if len == 0 {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.memory.as_mut().bytes, len) }
+    }
+
+    /// Grows the memory by `delta` pages like `memory.grow`, returning the previous size in pages or
+    /// `None` if it would exceed the maximum or the pages can't be allocated
+    pub fn grow(&mut self, delta: u32) -> Option<u32> {
+        match unsafe { ffi::memory_grow(self.memory.as_ptr(), delta) } {
+            u32::MAX => None,
+            prev => Some(prev),
+        }
+    }
+
+    /// Fills `buf` with the bytes at `offset`
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+        buf.copy_from_slice(self.data().get(range(offset, buf.len())?).ok_or_else(out_of_bounds)?);
+        Ok(())
+    }
+
+    /// Copies `buf` to `offset`
+    pub fn write(&mut self, offset: u32, buf: &[u8]) -> Result<(), Error> {
+        let range = range(offset, buf.len())?;
+        self.data_mut().get_mut(range).ok_or_else(out_of_bounds)?.copy_from_slice(buf);
+        Ok(())
+    }
+
+    accessors! {
+        read_u8, write_u8, u8;
+        read_i32, write_i32, i32;
+        read_u32, write_u32, u32;
+        read_i64, write_i64, i64;
+        read_u64, write_u64, u64;
+        read_f32, write_f32, f32;
+        read_f64, write_f64, f64;
+    }
+}
+
+fn range(offset: u32, len: usize) -> Result<std::ops::Range<usize>, Error> {
+    let start = offset as usize;
+    Ok(start..start.checked_add(len).ok_or_else(out_of_bounds)?)
+}
+
+fn out_of_bounds() -> Error {
+    Error::Trap(Trap::new(TrapKind::MemoryOutOfBounds, "out of bounds memory access"))
+}
@@ -0,0 +1,60 @@
+use wasm89::{Error, Instance, Module, TrapKind, Value};
+
+/// `(func (export "add") (param i32 i32) (result i32) ...)` and the same for `div_s`
+const ARITH: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+    0x03, 0x03, 0x02, 0x00, 0x00,
+    0x07, 0x0d, 0x02, 0x03, b'a', b'd', b'd', 0x00, 0x00, 0x03, b'd', b'i', b'v', 0x00, 0x01,
+    0x0a, 0x11, 0x02,
+    0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+    0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6d, 0x0b,
+];
+
+fn instance() -> Instance {
+    wasm89::set_log_level(0);
+    Instance::new(&Module::new(ARITH).unwrap()).unwrap()
+}
+
+#[test]
+fn call_returns_results() {
+    let mut i = instance();
+    assert_eq!(i.call("add", &[Value::I32(1), Value::I32(2)]).unwrap(), vec![Value::I32(3)]);
+    assert_eq!(i.call("div", &[Value::I32(-9), Value::I32(2)]).unwrap(), vec![Value::I32(-4)]);
+}
+
+#[test]
+fn trap_leaves_instance_usable() {
+    let mut i = instance();
+    let e = i.call("div", &[Value::I32(1), Value::I32(0)]).unwrap_err();
+    assert_eq!(e.trap().unwrap().kind, TrapKind::IntegerDivideByZero);
+    assert_eq!(e.to_string(), "integer divide by zero");
+    assert_eq!(i.call("add", &[Value::I32(2), Value::I32(2)]).unwrap(), vec![Value::I32(4)]);
+}
+
+#[test]
+fn bad_calls_are_rejected() {
+    let mut i = instance();
+    assert!(matches!(i.call("sub", &[]), Err(Error::Call(_))));
+    assert!(matches!(i.call("add", &[Value::I32(1)]), Err(Error::Call(_))));
+    assert!(matches!(i.call("add", &[Value::I32(1), Value::I64(2)]), Err(Error::Call(_))));
+}
+
+/// `(func (export "big") (local i32 ...))` with 60000 locals, which fit in the stack once but not
+/// twice as `setup_call` lays them out
+const BIG_FRAME: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+    0x03, 0x02, 0x01, 0x00,
+    0x07, 0x07, 0x01, 0x03, b'b', b'i', b'g', 0x00, 0x00,
+    0x0a, 0x08, 0x01, 0x06, 0x01, 0xe0, 0xd4, 0x03, 0x7f, 0x0b,
+];
+
+#[test]
+fn frame_too_big_for_the_stack_traps() {
+    wasm89::set_log_level(0);
+    let mut i = Instance::new(&Module::new(BIG_FRAME).unwrap()).unwrap();
+    let e = i.call("big", &[]).unwrap_err();
+    assert_eq!(e.trap().unwrap().kind, TrapKind::CallStackExhausted);
+    assert_eq!(i.call("big", &[]).unwrap_err().trap().unwrap().kind, TrapKind::CallStackExhausted);
+}
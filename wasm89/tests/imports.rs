@@ -0,0 +1,61 @@
+use std::cell::Cell;
+use std::error::Error as _;
+use std::rc::Rc;
+
+use wasm89::{Error, HostError, Imports, Instance, Module, Value};
+
+/// `(import "env" "double" (func (param i32) (result i32)))` called by the export `run`
+const DOUBLE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+    0x02, 0x0e, 0x01, 0x03, b'e', b'n', b'v', 0x06, b'd', b'o', b'u', b'b', b'l', b'e', 0x00, 0x00,
+    0x03, 0x02, 0x01, 0x00,
+    0x07, 0x07, 0x01, 0x03, b'r', b'u', b'n', 0x00, 0x01,
+    0x0a, 0x08, 0x01, 0x06, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b,
+];
+
+fn instance(imports: Imports) -> Result<Instance, Error> {
+    wasm89::set_log_level(0);
+    Instance::new(&Module::with_imports(DOUBLE, imports)?)
+}
+
+#[test]
+fn closure_is_called_with_the_arguments() {
+    let calls = Rc::new(Cell::new(0));
+    let mut imports = Imports::new();
+    let c = calls.clone();
+    imports.func("env", "double", move |_, args| {
+        c.set(c.get() + 1);
+        match args {
+            [Value::I32(x)] => Ok(vec![Value::I32(x * 2)]),
+            _ => unreachable!(),
+        }
+    });
+
+    let mut i = instance(imports).unwrap();
+    assert_eq!(i.call("run", &[Value::I32(21)]).unwrap(), vec![Value::I32(42)]);
+    assert_eq!(i.call("run", &[Value::I32(-3)]).unwrap(), vec![Value::I32(-6)]);
+    assert_eq!(calls.get(), 2);
+}
+
+#[test]
+fn host_errors_are_returned_by_call() {
+    let mut imports = Imports::new();
+    imports.func("env", "double", |_, _| Err(HostError::new("no doubling today")));
+    let mut i = instance(imports).unwrap();
+    let e = i.call("run", &[Value::I32(1)]).unwrap_err();
+    assert!(matches!(e, Error::Host(_)));
+    assert_eq!(e.source().unwrap().to_string(), "no doubling today");
+
+    let mut imports = Imports::new();
+    imports.func("env", "double", |_, _| Ok(vec![Value::I64(1)]));
+    let mut i = instance(imports).unwrap();
+    assert!(i.call("run", &[Value::I32(1)]).is_err());
+}
+
+#[test]
+fn missing_import_fails_the_load() {
+    let Err(Error::Load(e)) = instance(Imports::new()) else { panic!("loaded without its import") };
+    assert_eq!(e.message, "unknown import env.double");
+    assert_eq!(e.offset, Some(0x13));
+}
@@ -0,0 +1,211 @@
+use wasm89::{Error, Imports, Instance, LoadError, Module, TrapKind, Value};
+
+const HEADER: &[u8] = &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+/// `(func unreachable) (start 0)`
+const START_TRAPS: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+    0x03, 0x02, 0x01, 0x00,
+    0x08, 0x01, 0x00,
+    0x0a, 0x05, 0x01, 0x03, 0x00, 0x00, 0x0b,
+];
+
+/// `(memory 1) (global (mut i32) (i32.const 10)) (data (i32.const 0) "*")` and
+/// `(func (export "bump") (result i32) ...)` adding 1 to the global and returning it
+const COUNTER: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
+    0x03, 0x02, 0x01, 0x00,
+    0x05, 0x03, 0x01, 0x00, 0x01,
+    0x06, 0x06, 0x01, 0x7f, 0x01, 0x41, 0x0a, 0x0b,
+    0x07, 0x08, 0x01, 0x04, b'b', b'u', b'm', b'p', 0x00, 0x00,
+    0x0a, 0x0d, 0x01, 0x0b, 0x00, 0x23, 0x00, 0x41, 0x01, 0x6a, 0x24, 0x00, 0x23, 0x00, 0x0b,
+    0x0b, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, b'*',
+];
+
+fn load(bytes: &[u8]) -> Result<Instance, Error> {
+    wasm89::set_log_level(0);
+    Instance::new(&Module::new(bytes)?)
+}
+
+fn load_error(bytes: &[u8]) -> LoadError {
+    match load(bytes) {
+        Err(Error::Load(e)) => e,
+        Err(e) => panic!("expected a load error, got {e}"),
+        Ok(_) => panic!("expected a load error, the module loaded"),
+    }
+}
+
+#[test]
+fn header_is_checked() {
+    let e = load_error(b"\0wasm\x01\0\0\0");
+    assert_eq!(e.message, "magic header not detected");
+    assert_eq!(e.offset, Some(0));
+
+    let e = load_error(&[&HEADER[..4], &[0x02, 0x00, 0x00, 0x00]].concat());
+    assert_eq!(e.offset, Some(4));
+
+    assert_eq!(load_error(&HEADER[..6]).offset, Some(6));
+}
+
+#[test]
+fn errors_have_the_offset_of_the_bad_bytes() {
+    let e = load_error(&[HEADER, &[0x0d, 0x00]].concat());
+    assert_eq!(e.message, "invalid section id");
+    assert_eq!(e.offset, Some(8));
+
+    // A memory section with two memories
+    let e = load_error(&[HEADER, &[0x05, 0x05, 0x02, 0x00, 0x01, 0x00, 0x01]].concat());
+    assert_eq!(e.message, "multiple memories");
+    assert_eq!(e.offset, Some(10));
+    assert_eq!(e.to_string(), "multiple memories at byte 0xa");
+}
+
+#[test]
+fn modules_are_validated_before_imports_fail_to_link() {
+    // `(import "env" "mem" (memory 1)) (memory 1)`, with no env.mem to import
+    let imports = [0x02, 0x0c, 0x01, 0x03, b'e', b'n', b'v', 0x03, b'm', b'e', b'm', 0x02, 0x00, 0x01];
+    let e = load_error(&[HEADER, &imports, &[0x05, 0x03, 0x01, 0x00, 0x01]].concat());
+    assert_eq!(e.message, "multiple memories");
+    assert_eq!(e.offset, Some(24));
+
+    let e = load_error(&[HEADER, &imports].concat());
+    assert_eq!(e.message, "unknown import env.mem");
+    assert_eq!(e.offset, Some(11));
+}
+
+#[test]
+fn reads_stop_at_the_end_of_the_section() {
+    // A type section 5 bytes long, with 1 byte left in the module
+    let e = load_error(&[HEADER, &[0x01, 0x05, 0x01]].concat());
+    assert_eq!(e.message, "unexpected end of section or function");
+    assert_eq!(e.offset, Some(10));
+
+    // A type section with a byte after its (empty) list of types
+    let e = load_error(&[HEADER, &[0x01, 0x02, 0x00, 0x00]].concat());
+    assert_eq!(e.message, "section size mismatch");
+    assert_eq!(e.offset, Some(8));
+
+    // A custom section whose name is 4GiB long
+    let e = load_error(&[HEADER, &[0x00, 0x05, 0xff, 0xff, 0xff, 0xff, 0x0f]].concat());
+    assert_eq!(e.message, "unexpected end of section or function");
+    assert_eq!(e.offset, Some(15));
+}
+
+#[test]
+fn overlong_integers_are_rejected() {
+    let e = load_error(&[HEADER, &[0x01, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00]].concat());
+    assert_eq!(e.message, "integer representation too long");
+    assert_eq!(e.offset, Some(9));
+
+    // The 5th byte of a u32 only has 4 bits
+    let e = load_error(&[HEADER, &[0x01, 0x80, 0x80, 0x80, 0x80, 0x10]].concat());
+    assert_eq!(e.message, "integer too large");
+    assert_eq!(e.offset, Some(9));
+}
+
+#[test]
+fn function_bodies_are_validated() {
+    // (func (result i32) i64.const 0)
+    let e = load_error(&[
+        HEADER,
+        &[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+        &[0x03, 0x02, 0x01, 0x00],
+        &[0x0a, 0x06, 0x01, 0x04, 0x00, 0x42, 0x00, 0x0b],
+    ]
+    .concat());
+    assert_eq!(e.message, "type mismatch");
+    assert_eq!(e.offset, Some(0x1a));
+
+    // (global i32 (i32.const 0)) (func i32.const 0 global.set 0)
+    let e = load_error(&[
+        HEADER,
+        &[0x01, 0x04, 0x01, 0x60, 0x00, 0x00],
+        &[0x03, 0x02, 0x01, 0x00],
+        &[0x06, 0x06, 0x01, 0x7f, 0x00, 0x41, 0x00, 0x0b],
+        &[0x0a, 0x08, 0x01, 0x06, 0x00, 0x41, 0x00, 0x24, 0x00, 0x0b],
+    ]
+    .concat());
+    assert_eq!(e.message, "global is immutable");
+    assert_eq!(e.offset, Some(0x21));
+}
+
+#[test]
+fn start_function_trap_is_returned() {
+    let e = load(START_TRAPS).err().unwrap();
+    assert!(matches!(e, Error::Nested { .. }));
+    assert_eq!(e.to_string(), "start function failed");
+    assert_eq!(e.trap().unwrap().kind, TrapKind::Unreachable);
+}
+
+#[test]
+fn instances_of_a_module_have_their_own_state() {
+    wasm89::set_log_level(0);
+    let module = Module::new(COUNTER).unwrap();
+    let mut a = Instance::new(&module).unwrap();
+    let mut b = Instance::new(&module).unwrap();
+    assert_eq!(a.call("bump", &[]).unwrap(), vec![Value::I32(11)]);
+    assert_eq!(a.call("bump", &[]).unwrap(), vec![Value::I32(12)]);
+    assert_eq!(b.call("bump", &[]).unwrap(), vec![Value::I32(11)]);
+
+    a.memory().write_u8(0, 1).unwrap();
+    assert_eq!(b.memory().read_u8(0).unwrap(), b'*');
+
+    // The instances keep the module alive
+    drop(module);
+    let mut c = Instance::new(b.module()).unwrap();
+    assert_eq!(c.memory().read_u8(0).unwrap(), b'*');
+    assert_eq!(c.call("bump", &[]).unwrap(), vec![Value::I32(11)]);
+}
+
+#[test]
+fn static_modules_are_read_in_place() {
+    wasm89::set_log_level(0);
+    // Constants are in read only memory, a write to them would fault
+    let bytes: &'static [u8] = COUNTER;
+    let module = Module::from_static(bytes, Imports::new()).unwrap();
+    assert_eq!(module.bytes().as_ptr(), bytes.as_ptr());
+
+    let mut a = Instance::new(&module).unwrap();
+    let mut b = Instance::new(&module).unwrap();
+    assert_eq!(a.call("bump", &[]).unwrap(), vec![Value::I32(11)]);
+    assert_eq!(b.call("bump", &[]).unwrap(), vec![Value::I32(11)]);
+    a.memory().write_u8(0, 1).unwrap();
+    assert_eq!(b.memory().read_u8(0).unwrap(), b'*');
+}
+
+#[test]
+fn dropped_instances_are_freed() {
+    // (table 1 funcref) (memory 1) (global (mut i32) (i32.const 0))
+    // (func (export "f") (local i32) block end) (elem (i32.const 0) 0) (data (i32.const 0) "*")
+    // and a custom section, every section allocates something
+    let bytes = [
+        HEADER,
+        &[0x01, 0x04, 0x01, 0x60, 0x00, 0x00],
+        &[0x03, 0x02, 0x01, 0x00],
+        &[0x04, 0x04, 0x01, 0x70, 0x00, 0x01],
+        &[0x05, 0x03, 0x01, 0x00, 0x01],
+        &[0x06, 0x06, 0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b],
+        &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+        &[0x09, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, 0x00],
+        &[0x0a, 0x09, 0x01, 0x07, 0x01, 0x01, 0x7f, 0x02, 0x40, 0x0b, 0x0b],
+        &[0x0b, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, 0x2a],
+        &[0x00, 0x03, 0x02, b'h', b'i'],
+    ]
+    .concat();
+
+    // The asan build checks for leaks at exit
+    for _ in 0..10 {
+        let mut instance = load(&bytes).unwrap();
+        assert_eq!(instance.call("f", &[]).unwrap(), vec![]);
+    }
+}
+
+#[test]
+fn loading_continues_after_a_bad_module() {
+    for _ in 0..3 {
+        assert!(load(HEADER).is_ok());
+        assert!(matches!(load(&HEADER[..7]), Err(Error::Load(_))));
+    }
+}
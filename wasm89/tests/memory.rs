@@ -0,0 +1,75 @@
+use wasm89::{Imports, Instance, Module, Value};
+
+/// `(memory 1 2)` and `(func (export "load") (param i32) (result i32) (i32.load (local.get 0)))`
+const LOAD: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+    0x03, 0x02, 0x01, 0x00,
+    0x05, 0x04, 0x01, 0x01, 0x01, 0x02,
+    0x07, 0x08, 0x01, 0x04, b'l', b'o', b'a', b'd', 0x00, 0x00,
+    0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x28, 0x02, 0x00, 0x0b,
+];
+
+fn instance() -> Instance {
+    wasm89::set_log_level(0);
+    Instance::new(&Module::new(LOAD).unwrap()).unwrap()
+}
+
+#[test]
+fn writes_are_seen_by_wasm() {
+    let mut i = instance();
+    i.memory().write_i32(8, -2).unwrap();
+    i.memory().write(16, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(i.call("load", &[Value::I32(8)]).unwrap(), vec![Value::I32(-2)]);
+    assert_eq!(i.call("load", &[Value::I32(16)]).unwrap(), vec![Value::I32(0x04030201)]);
+
+    let mut buf = [0; 4];
+    i.memory().read(8, &mut buf).unwrap();
+    assert_eq!(buf, (-2i32).to_le_bytes());
+    assert_eq!(i.memory().read_u8(17).unwrap(), 2);
+    assert_eq!(i.memory().read_f64(0).unwrap(), 0.0);
+}
+
+#[test]
+fn accesses_are_bounds_checked() {
+    let mut i = instance();
+    let mut memory = i.memory();
+    assert_eq!(memory.data().len(), 65536);
+    assert!(memory.write_u32(65533, 1).is_err());
+    assert!(memory.read_u64(u32::MAX).is_err());
+    assert!(memory.write_u32(65532, 1).is_ok());
+}
+
+#[test]
+fn grow_up_to_the_maximum() {
+    let mut i = instance();
+    let mut memory = i.memory();
+    assert_eq!(memory.grow(1).unwrap(), 1);
+    assert_eq!(memory.size_pages(), 2);
+    assert_eq!(memory.grow(1), None);
+    memory.write_u32(65536 + 4, 7).unwrap();
+    assert_eq!(i.call("load", &[Value::I32(65536 + 4)]).unwrap(), vec![Value::I32(7)]);
+}
+
+#[test]
+fn host_functions_see_the_caller_memory() {
+    /// `(import "env" "fill" (func))`, `(memory 1)` and `(func (export "run") (result i32) (call 0) (i32.load (i32.const 0)))`
+    const FILL: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x08, 0x02, 0x60, 0x00, 0x00, 0x60, 0x00, 0x01, 0x7f,
+        0x02, 0x0c, 0x01, 0x03, b'e', b'n', b'v', 0x04, b'f', b'i', b'l', b'l', 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x01,
+        0x05, 0x03, 0x01, 0x00, 0x01,
+        0x07, 0x07, 0x01, 0x03, b'r', b'u', b'n', 0x00, 0x01,
+        0x0a, 0x0b, 0x01, 0x09, 0x00, 0x10, 0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x0b,
+    ];
+
+    wasm89::set_log_level(0);
+    let mut imports = Imports::new();
+    imports.func("env", "fill", |caller, _| {
+        caller.memory().write_i32(0, 1234)?;
+        Ok(vec![])
+    });
+    let mut i = Instance::new(&Module::with_imports(FILL, imports).unwrap()).unwrap();
+    assert_eq!(i.call("run", &[]).unwrap(), vec![Value::I32(1234)]);
+}